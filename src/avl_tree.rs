@@ -0,0 +1,428 @@
+//! Self-balancing AVL tree.
+//!
+//! Every update copies the path from the changed node up to the root, and
+//! the rotations copy the few nodes they touch, so the old root still sees
+//! the old tree. The height of each node is persisted in `TreeNodeHD`.
+
+use std::cell::RefCell;
+use std::cmp::{max, Ordering};
+use std::marker::PhantomData;
+use std::ops::Bound;
+use std::rc::Rc;
+
+use anyhow::Result;
+use log::debug;
+
+use crate::comparator::{Bytewise, Comparator};
+use crate::logical_tree::{
+    store_balanced, store_sorted, subtree_rank, subtree_select, subtree_size, Agent, DBTree,
+    NodeAgentCell, NodeCursor, StringAgent, TreeNode, TreeNodeAgent,
+};
+use crate::serde_interface::{SerdeInterface, SerdeJson};
+use crate::storage::Storage;
+
+type Node<V, S> = TreeNode<V, TreeNodeAgent<V, S>>;
+// (modified_node, min_node)
+type DelMinResult<V, S> = (Option<NodeAgentCell<V, S>>, Node<V, S>);
+
+/// An AVL tree with byte keys. The heights of the two subtrees of any node
+/// differ by at most one, so the depth of the tree is O(log n) however keys
+/// arrive.
+///
+/// `S`: how to serialize / deserialize tree nodes and values
+///
+/// `V`: the agent of values, like `StringAgent`, `BytesAgent` or `SerdeAgent`
+///
+/// `C`: the order of keys
+pub struct AvlTree<S = SerdeJson, V = StringAgent<S>, C = Bytewise> {
+    root: Option<NodeAgentCell<V, S>>,
+    order: PhantomData<C>,
+}
+
+impl<S: SerdeInterface, V: Agent, C: Comparator> AvlTree<S, V, C> {
+    fn _node(agent: &NodeAgentCell<V, S>, storage: &mut impl Storage) -> Result<Node<V, S>> {
+        let mut agent = agent.borrow_mut();
+        Ok(agent.get(storage)?.unwrap().clone())
+    }
+
+    fn _height(agent: &Option<NodeAgentCell<V, S>>, storage: &mut impl Storage) -> Result<usize> {
+        match agent {
+            Some(agent) => Ok(agent.borrow_mut().get(storage)?.unwrap().height),
+            None => Ok(0),
+        }
+    }
+
+    fn _size(agent: &Option<NodeAgentCell<V, S>>, storage: &mut impl Storage) -> Result<usize> {
+        match agent {
+            Some(agent) => Ok(agent.borrow_mut().get(storage)?.unwrap().size),
+            None => Ok(0),
+        }
+    }
+
+    // fix height and size of a copied node and wrap it in a new agent
+    fn _make(mut node: Node<V, S>, storage: &mut impl Storage) -> Result<NodeAgentCell<V, S>> {
+        let left_height = Self::_height(&node.left_agent, storage)?;
+        let right_height = Self::_height(&node.right_agent, storage)?;
+        node.height = 1 + max(left_height, right_height);
+        node.size =
+            1 + Self::_size(&node.left_agent, storage)? + Self::_size(&node.right_agent, storage)?;
+        Ok(rc!(TreeNodeAgent::<V, S>::new(Some(node), None)))
+    }
+
+    fn _rotate_left(
+        mut node: Node<V, S>,
+        storage: &mut impl Storage,
+    ) -> Result<NodeAgentCell<V, S>> {
+        let mut right = Self::_node(node.right_agent.as_ref().unwrap(), storage)?;
+        debug!("[_rotate_left] {:?} goes down to the left", node.key);
+        node.right_agent = right.left_agent.take();
+        right.left_agent = Some(Self::_make(node, storage)?);
+        Self::_make(right, storage)
+    }
+
+    fn _rotate_right(
+        mut node: Node<V, S>,
+        storage: &mut impl Storage,
+    ) -> Result<NodeAgentCell<V, S>> {
+        let mut left = Self::_node(node.left_agent.as_ref().unwrap(), storage)?;
+        debug!("[_rotate_right] {:?} goes down to the right", node.key);
+        node.left_agent = left.right_agent.take();
+        left.right_agent = Some(Self::_make(node, storage)?);
+        Self::_make(left, storage)
+    }
+
+    // restore the AVL property of a copied node whose subtrees differ in
+    // height by at most two
+    fn _balance(mut node: Node<V, S>, storage: &mut impl Storage) -> Result<NodeAgentCell<V, S>> {
+        let left_height = Self::_height(&node.left_agent, storage)?;
+        let right_height = Self::_height(&node.right_agent, storage)?;
+        if left_height > right_height + 1 {
+            let left = Self::_node(node.left_agent.as_ref().unwrap(), storage)?;
+            if Self::_height(&left.right_agent, storage)?
+                > Self::_height(&left.left_agent, storage)?
+            {
+                node.left_agent = Some(Self::_rotate_left(left, storage)?);
+            }
+            Self::_rotate_right(node, storage)
+        } else if right_height > left_height + 1 {
+            let right = Self::_node(node.right_agent.as_ref().unwrap(), storage)?;
+            if Self::_height(&right.left_agent, storage)?
+                > Self::_height(&right.right_agent, storage)?
+            {
+                node.right_agent = Some(Self::_rotate_right(right, storage)?);
+            }
+            Self::_rotate_left(node, storage)
+        } else {
+            Self::_make(node, storage)
+        }
+    }
+
+    fn _find(
+        &self,
+        key: &[u8],
+        mut agent: Option<NodeAgentCell<V, S>>,
+        storage: &mut impl Storage,
+    ) -> Result<Option<Rc<RefCell<V>>>> {
+        while let Some(current) = agent {
+            let mut current = current.borrow_mut();
+            let node = current.get(storage)?.unwrap();
+            agent = match C::compare(key, &node.key) {
+                Ordering::Less => node.left_agent.clone(),
+                Ordering::Greater => node.right_agent.clone(),
+                Ordering::Equal => return Ok(Some(node.value_agent.clone())),
+            };
+        }
+        Ok(None)
+    }
+
+    fn _insert(
+        &mut self,
+        key: Vec<u8>,
+        value: V::Inner,
+        agent: Option<NodeAgentCell<V, S>>,
+        storage: &mut impl Storage,
+    ) -> Result<NodeAgentCell<V, S>> {
+        if let Some(agent) = agent {
+            let mut node = Self::_node(&agent, storage)?;
+            match C::compare(&key, &node.key) {
+                Ordering::Less => {
+                    let left = node.left_agent.take();
+                    node.left_agent = Some(self._insert(key, value, left, storage)?);
+                }
+                Ordering::Greater => {
+                    let right = node.right_agent.take();
+                    node.right_agent = Some(self._insert(key, value, right, storage)?);
+                }
+                Ordering::Equal => {
+                    node.value_agent = rc!(V::new(Some(value), None));
+                }
+            }
+            Self::_balance(node, storage)
+        } else {
+            debug!("[_insert] New a TreeNode with key {:?}", key);
+            Ok(rc!(TreeNodeAgent::<V, S>::new(
+                Some(TreeNode::new(key, value)),
+                None
+            )))
+        }
+    }
+
+    // return (modified_node, min_node)
+    fn _delmin(
+        &mut self,
+        agent: &NodeAgentCell<V, S>,
+        storage: &mut impl Storage,
+    ) -> Result<DelMinResult<V, S>> {
+        let mut node = Self::_node(agent, storage)?;
+        match node.left_agent.take() {
+            None => Ok((node.right_agent.clone(), node)),
+            Some(left) => {
+                let (modified, min_node) = self._delmin(&left, storage)?;
+                node.left_agent = modified;
+                Ok((Some(Self::_balance(node, storage)?), min_node))
+            }
+        }
+    }
+
+    fn _delete(
+        &mut self,
+        key: &[u8],
+        agent: Option<NodeAgentCell<V, S>>,
+        storage: &mut impl Storage,
+    ) -> Result<Option<NodeAgentCell<V, S>>> {
+        let agent = match agent {
+            Some(agent) => agent,
+            None => return Ok(None),
+        };
+        let mut node = Self::_node(&agent, storage)?;
+        match C::compare(key, &node.key) {
+            Ordering::Less => {
+                let left = node.left_agent.take();
+                node.left_agent = self._delete(key, left, storage)?;
+            }
+            Ordering::Greater => {
+                let right = node.right_agent.take();
+                node.right_agent = self._delete(key, right, storage)?;
+            }
+            Ordering::Equal => match (node.left_agent.take(), node.right_agent.take()) {
+                (None, right) => return Ok(right),
+                (left, None) => return Ok(left),
+                (left, Some(right)) => {
+                    let (modified, min_node) = self._delmin(&right, storage)?;
+                    node.key = min_node.key;
+                    node.value_agent = min_node.value_agent;
+                    node.left_agent = left;
+                    node.right_agent = modified;
+                }
+            },
+        }
+        Ok(Some(Self::_balance(node, storage)?))
+    }
+}
+
+impl<S: SerdeInterface, V: Agent, C: Comparator> DBTree for AvlTree<S, V, C> {
+    type Value = V::Inner;
+    type Comparator = C;
+
+    type Format = S;
+
+    fn new() -> Result<Self> {
+        Ok(AvlTree {
+            root: None,
+            order: PhantomData,
+        })
+    }
+
+    fn change_view(&mut self, addr: u64) -> Result<()> {
+        self.root = Some(rc!(TreeNodeAgent::<V, S>::new(None, Some(addr))));
+        Ok(())
+    }
+
+    fn store(&mut self, storage: &mut impl Storage) -> Result<Option<u64>> {
+        if let Some(ref root) = self.root {
+            root.borrow_mut().store(storage)?;
+            let addr = root.borrow().addr().unwrap();
+            Ok(Some(addr))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn find(&mut self, key: &[u8], storage: &mut impl Storage) -> Result<Option<Self::Value>> {
+        let agent = self.root.as_ref().cloned();
+        if let Some(agent) = self._find(key, agent, storage)? {
+            return Ok(agent.borrow_mut().get(storage)?.cloned());
+        }
+        Ok(None)
+    }
+
+    fn insert(
+        &mut self,
+        key: Vec<u8>,
+        value: Self::Value,
+        storage: &mut impl Storage,
+    ) -> Result<()> {
+        let agent = self.root.as_ref().cloned();
+        self.root = Some(self._insert(key, value, agent, storage)?);
+        Ok(())
+    }
+
+    fn delete(&mut self, key: &[u8], storage: &mut impl Storage) -> Result<()> {
+        let agent = self.root.as_ref().cloned();
+        if self._find(key, agent.clone(), storage)?.is_some() {
+            debug!("[delete] found key {:?}", key);
+            self.root = self._delete(key, agent, storage)?;
+        }
+        Ok(())
+    }
+
+    fn unload(&mut self) {
+        if let Some(ref root) = self.root {
+            root.borrow_mut().unload();
+        }
+    }
+
+    fn bulk_load<I>(&mut self, pairs: I, storage: &mut impl Storage) -> Result<()>
+    where
+        I: Iterator<Item = (Vec<u8>, Self::Value)>,
+    {
+        // a perfectly balanced tree is an AVL tree
+        let entries = store_sorted::<V, C, _>(pairs, storage)?;
+        let root = store_balanced::<S>(&entries, storage)?;
+        self.root = root.map(|root| rc!(TreeNodeAgent::<V, S>::new(None, Some(root.addr))));
+        Ok(())
+    }
+
+    fn len(&mut self, storage: &mut impl Storage) -> Result<usize> {
+        subtree_size(&self.root, storage)
+    }
+
+    fn rank(&mut self, key: &[u8], inclusive: bool, storage: &mut impl Storage) -> Result<usize> {
+        subtree_rank::<V, S, C>(self.root.clone(), key, inclusive, storage)
+    }
+
+    fn select(&mut self, n: usize, storage: &mut impl Storage) -> Result<Option<Vec<u8>>> {
+        subtree_select(self.root.clone(), n, storage)
+    }
+
+    fn copy(addr: u64, from: &mut impl Storage, to: &mut impl Storage) -> Result<u64> {
+        TreeNodeAgent::<V, S>::copy(addr, from, to)
+    }
+
+    type Cursor = NodeCursor<V, S, C>;
+
+    fn range(&self, range: (Bound<Vec<u8>>, Bound<Vec<u8>>)) -> Self::Cursor {
+        NodeCursor::new(self.root.as_ref().cloned(), range)
+    }
+}
+
+#[cfg(test)]
+mod avl_test {
+    use super::*;
+    use crate::logical_tree::LogicalTree;
+    use crate::storage::FileStorage;
+    use tempfile;
+
+    // check the AVL property and sizes, return (height, size)
+    fn check(
+        agent: &Option<NodeAgentCell<StringAgent, SerdeJson>>,
+        storage: &mut FileStorage,
+    ) -> (usize, usize) {
+        match agent {
+            None => (0, 0),
+            Some(agent) => {
+                let node = AvlTree::<SerdeJson>::_node(agent, storage).unwrap();
+                let (lh, ls) = check(&node.left_agent, storage);
+                let (rh, rs) = check(&node.right_agent, storage);
+                assert!(lh <= rh + 1 && rh <= lh + 1, "unbalanced at {:?}", node.key);
+                assert_eq!(1 + max(lh, rh), node.height);
+                assert_eq!(1 + ls + rs, node.size);
+                (node.height, node.size)
+            }
+        }
+    }
+
+    #[test]
+    fn test_avl_tree_sorted_insert() {
+        let path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+        let mut storage = FileStorage::new(&path).unwrap();
+        let mut tree = AvlTree::<SerdeJson>::new().unwrap();
+        for i in 0..1000 {
+            tree.insert(format!("{:04}", i).into(), i.to_string(), &mut storage)
+                .unwrap();
+        }
+        let addr = tree.store(&mut storage).unwrap().unwrap();
+
+        // read the tree back from disk
+        let mut tree = AvlTree::<SerdeJson>::new().unwrap();
+        tree.change_view(addr).unwrap();
+        let (height, size) = check(&tree.root, &mut storage);
+        assert_eq!(1000, size);
+        // 1.44 * log2(1000) is less than 15
+        assert!(height <= 15, "too high: {}", height);
+        assert_eq!(
+            Some("567".to_owned()),
+            tree.find(b"0567", &mut storage).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_avl_tree_delete() {
+        let path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+        let mut storage = FileStorage::new(&path).unwrap();
+        let mut tree = AvlTree::<SerdeJson>::new().unwrap();
+        for i in 0..200 {
+            tree.insert(format!("{:03}", i).into(), i.to_string(), &mut storage)
+                .unwrap();
+        }
+        for i in (0..200).filter(|i| i % 3 != 0) {
+            tree.delete(format!("{:03}", i).as_bytes(), &mut storage)
+                .unwrap();
+        }
+        tree.delete(b"nothing", &mut storage).unwrap();
+        let (_, size) = check(&tree.root, &mut storage);
+        assert_eq!(67, size);
+        assert_eq!(None, tree.find(b"001", &mut storage).unwrap());
+        assert_eq!(
+            Some("3".to_owned()),
+            tree.find(b"003", &mut storage).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_avl_tree_logical_tree() {
+        let path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+        let mut tree = LogicalTree::<AvlTree>::new(&path).unwrap();
+        tree.begin().unwrap();
+        for key in &["a", "b", "c", "d", "e"] {
+            tree.put(key.to_string(), key.to_uppercase()).unwrap();
+        }
+        tree.del("c").unwrap();
+        tree.commit().unwrap();
+        drop(tree);
+
+        let mut tree = LogicalTree::<AvlTree>::new(&path).unwrap();
+        assert_eq!(Some("D".to_owned()), tree.get("d").unwrap());
+        assert_eq!(None, tree.get("c").unwrap());
+        let keys: Vec<String> = tree
+            .iter()
+            .unwrap()
+            .map(|pair| String::from_utf8(pair.unwrap().0).unwrap())
+            .collect();
+        assert_eq!(vec!["a", "b", "d", "e"], keys);
+    }
+
+    #[test]
+    fn test_avl_tree_bulk_load() {
+        let path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+        let mut storage = FileStorage::new(&path).unwrap();
+        let mut tree = AvlTree::<SerdeJson>::new().unwrap();
+        let pairs = (0..1000).map(|i| (format!("{:04}", i).into_bytes(), i.to_string()));
+        tree.bulk_load(pairs, &mut storage).unwrap();
+        let (height, size) = check(&tree.root, &mut storage);
+        assert_eq!((10, 1000), (height, size));
+        tree.insert(b"1000".to_vec(), "1000".to_owned(), &mut storage)
+            .unwrap();
+        assert_eq!((10, 1001), check(&tree.root, &mut storage));
+    }
+}
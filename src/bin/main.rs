@@ -1 +1,187 @@
-fn main() {}
+//! `dbdb` command-line tool.
+//!
+//! ```text
+//! dbdb <file> get <key>
+//! dbdb <file> put <key> [value]    read the value from stdin if it's missing,
+//!                                  without one trailing newline
+//! dbdb <file> del <key>
+//! dbdb <file> scan [start] [end]   keys in [start, end)
+//! dbdb <file> stats
+//! ```
+//!
+//! Every result is printed as one JSON object per line, errors are printed to
+//! stderr as `{"error": ...}`. Keys are strings if they are valid UTF-8, or
+//! `{"hex": ...}` if not. Only `put` creates a missing file.
+
+use std::env;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::ops::Bound;
+use std::process;
+
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+use serde_json::json;
+
+use dbdb::serde_interface::compact_bytes;
+use dbdb::OpenOptions;
+
+const USAGE: &str =
+    "usage: dbdb <file> (get <key> | put <key> [value] | del <key> | scan [start] [end] | stats)";
+
+// a key as the crate serializes it
+#[derive(Serialize)]
+struct Key<'a>(#[serde(with = "compact_bytes")] &'a [u8]);
+
+fn run(args: &[String], input: &mut impl Read, out: &mut impl Write) -> Result<()> {
+    let (path, cmd, rest) = match args {
+        [path, cmd, rest @ ..] => (path, cmd.as_str(), rest),
+        _ => return Err(anyhow!(USAGE)),
+    };
+    let mut db = OpenOptions::new().create(cmd == "put").open(path)?;
+    match (cmd, rest) {
+        ("get", [key]) => {
+            let value = db.get(key)?;
+            writeln!(out, "{}", json!({ "key": key, "value": value }))?;
+        }
+        ("put", [key]) => {
+            let mut value = String::new();
+            input.read_to_string(&mut value)?;
+            // `echo v | dbdb <file> put k` stores "v"
+            if value.ends_with('\n') {
+                value.pop();
+                if value.ends_with('\r') {
+                    value.pop();
+                }
+            }
+            db.put(key.clone(), value)?;
+            writeln!(out, "{}", json!({ "key": key, "put": true }))?;
+        }
+        ("put", [key, value]) => {
+            db.put(key.clone(), value.clone())?;
+            writeln!(out, "{}", json!({ "key": key, "put": true }))?;
+        }
+        ("del", [key]) => {
+            // look it up in the same transaction, to report what is deleted
+            db.begin()?;
+            let deleted = db.get(key)?.is_some();
+            db.del(key)?;
+            db.commit()?;
+            writeln!(out, "{}", json!({ "key": key, "deleted": deleted }))?;
+        }
+        ("scan", bounds) if bounds.len() <= 2 => {
+            let start = bounds
+                .first()
+                .map_or(Bound::Unbounded, |k| Bound::Included(k.as_str()));
+            let end = bounds
+                .get(1)
+                .map_or(Bound::Unbounded, |k| Bound::Excluded(k.as_str()));
+            for item in db.range::<&str, _>((start, end))? {
+                let (key, value) = item?;
+                let line = json!({ "key": Key(&key), "value": value });
+                writeln!(out, "{}", line)?;
+            }
+        }
+        ("stats", []) => {
+            let keys = db.len()?;
+            let bytes = fs::metadata(path)?.len();
+            writeln!(
+                out,
+                "{}",
+                json!({ "file": path, "keys": keys, "bytes": bytes })
+            )?;
+        }
+        _ => return Err(anyhow!(USAGE)),
+    }
+    Ok(())
+}
+
+fn main() {
+    pretty_env_logger::init();
+    let args: Vec<String> = env::args().skip(1).collect();
+    if let Err(e) = run(&args, &mut io::stdin().lock(), &mut io::stdout().lock()) {
+        eprintln!("{}", json!({ "error": format!("{:#}", e) }));
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod main_test {
+    use super::run;
+    use dbdb::{BinaryTree, LogicalTree};
+
+    // run a command on the file at `path`, return its output
+    fn dbdb(path: &str, args: &[&str]) -> anyhow::Result<String> {
+        dbdb_with_input(path, args, "")
+    }
+
+    // run a command reading `input` as its stdin
+    fn dbdb_with_input(path: &str, args: &[&str], input: &str) -> anyhow::Result<String> {
+        let args: Vec<String> = std::iter::once(path)
+            .chain(args.iter().copied())
+            .map(String::from)
+            .collect();
+        let mut out = Vec::new();
+        run(&args, &mut input.as_bytes(), &mut out)?;
+        Ok(String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn test_dbdb_commands() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.db");
+        let path = path.to_str().unwrap();
+
+        // only put creates the file
+        assert!(dbdb(path, &["get", "k"]).is_err());
+        assert!(dbdb(path, &["stats"]).is_err());
+        assert!(!dir.path().join("test.db").exists());
+
+        assert_eq!(
+            "{\"key\":\"k\",\"put\":true}\n",
+            dbdb(path, &["put", "k", "v"]).unwrap()
+        );
+        assert_eq!(
+            "{\"key\":\"k\",\"value\":\"v\"}\n",
+            dbdb(path, &["get", "k"]).unwrap()
+        );
+        assert!(dbdb(path, &["stats"]).unwrap().contains("\"keys\":1"));
+        assert_eq!(
+            "{\"deleted\":true,\"key\":\"k\"}\n",
+            dbdb(path, &["del", "k"]).unwrap()
+        );
+        assert!(dbdb(path, &["get"]).is_err());
+    }
+
+    #[test]
+    fn test_dbdb_put_from_stdin() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.db");
+        let path = path.to_str().unwrap();
+
+        // like `echo v | dbdb <file> put k`, only one newline is dropped
+        for (input, value) in [("v\n", "v"), ("v\r\n", "v"), ("v\n\n", "v\n"), ("v", "v")] {
+            dbdb_with_input(path, &["put", "k"], input).unwrap();
+            let expected = serde_json::json!({ "key": "k", "value": value });
+            assert_eq!(
+                format!("{}\n", expected),
+                dbdb(path, &["get", "k"]).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn test_dbdb_scan_binary_keys() {
+        let path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+        let mut db = LogicalTree::<BinaryTree>::new(&path).unwrap();
+        db.put(vec![0xff, 0x00], "binary".to_owned()).unwrap();
+        db.put(b"text".to_vec(), "text".to_owned()).unwrap();
+        drop(db);
+        let out = dbdb(path.to_str().unwrap(), &["scan"]).unwrap();
+        assert_eq!(
+            "{\"key\":\"text\",\"value\":\"text\"}\n\
+             {\"key\":{\"hex\":\"ff00\"},\"value\":\"binary\"}\n",
+            out
+        );
+    }
+}
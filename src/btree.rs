@@ -0,0 +1,1163 @@
+//! Copy-on-write B+tree.
+//!
+//! Nodes hold many keys, sized to fit a page, so a lookup reads only a few
+//! nodes. Like the binary trees, a node is never changed in place: an update
+//! copies the path from the leaf to the root. Thus leaves are not linked to
+//! each other, a range scan walks the tree with a stack instead.
+
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::io::{self, Write};
+use std::marker::PhantomData;
+use std::mem;
+use std::ops::Bound;
+use std::rc::Rc;
+
+use serde::{Deserialize, Serialize};
+
+use anyhow::Result;
+use log::debug;
+
+use crate::cache::HeapSize;
+use crate::comparator::{Bytewise, Comparator};
+use crate::logical_tree::{store_sorted, Agent, Cursor, DBTree, StringAgent};
+use crate::serde_interface::{compact_bytes, compact_bytes_seq, SerdeInterface, SerdeJson};
+use crate::storage::Storage;
+
+/// The default page size of `BTree`, 4 KiB
+pub const DEFAULT_PAGE_SIZE: usize = 4096;
+
+// the most bytes of a serialized node besides its entries, and of an
+// address or a size with its separator: 20 digits and a comma in json
+const NODE_OVERHEAD: usize = 64;
+const NUMBER_BYTES: usize = 21;
+
+type ValueAgentCell<V> = Rc<RefCell<V>>;
+type BNodeAgentCell<V, S> = Rc<RefCell<BNodeAgent<V, S>>>;
+// (left_half, Some((separator, right_half)))
+type SplitResult<V, S> = (BNode<V, S>, Option<(Vec<u8>, BNode<V, S>)>);
+
+/// BNode on Hard Disk.
+///
+/// A leaf keeps the addresses of its values. An internal node keeps the
+/// addresses of its children and the number of pairs under each of them,
+/// `keys[i]` is the smallest key under `children[i + 1]`.
+#[derive(Deserialize, Serialize, Clone)]
+enum BNodeHD {
+    Leaf {
+        #[serde(with = "compact_bytes_seq")]
+        keys: Vec<Vec<u8>>,
+        value_addrs: Vec<u64>,
+    },
+    Internal {
+        #[serde(with = "compact_bytes_seq")]
+        keys: Vec<Vec<u8>>,
+        child_addrs: Vec<u64>,
+        sizes: Vec<usize>,
+    },
+}
+
+impl HeapSize for BNodeHD {
+    fn heap_size(&self) -> usize {
+        let keys_size = |keys: &Vec<Vec<u8>>| {
+            keys.capacity() * mem::size_of::<Vec<u8>>()
+                + keys.iter().map(Vec::capacity).sum::<usize>()
+        };
+        match self {
+            BNodeHD::Leaf { keys, value_addrs } => {
+                keys_size(keys) + value_addrs.capacity() * mem::size_of::<u64>()
+            }
+            BNodeHD::Internal {
+                keys,
+                child_addrs,
+                sizes,
+            } => {
+                keys_size(keys)
+                    + child_addrs.capacity() * mem::size_of::<u64>()
+                    + sizes.capacity() * mem::size_of::<usize>()
+            }
+        }
+    }
+}
+
+enum Children<V, S> {
+    Leaf(Vec<ValueAgentCell<V>>),
+    Internal(Vec<BNodeAgentCell<V, S>>, Vec<usize>),
+}
+
+/// BNode in memory
+struct BNode<V, S> {
+    keys: Vec<Vec<u8>>,
+    children: Children<V, S>,
+}
+
+impl<V, S> Clone for BNode<V, S> {
+    fn clone(&self) -> Self {
+        let children = match self.children {
+            Children::Leaf(ref values) => Children::Leaf(values.clone()),
+            Children::Internal(ref children, ref sizes) => {
+                Children::Internal(children.clone(), sizes.clone())
+            }
+        };
+        BNode {
+            keys: self.keys.clone(),
+            children,
+        }
+    }
+}
+
+impl<V, S> BNode<V, S> {
+    fn leaf(keys: Vec<Vec<u8>>, values: Vec<ValueAgentCell<V>>) -> Self {
+        BNode {
+            keys,
+            children: Children::Leaf(values),
+        }
+    }
+
+    /// The number of KEY:VALUE pairs under this node
+    fn size(&self) -> usize {
+        match self.children {
+            Children::Leaf(ref values) => values.len(),
+            Children::Internal(_, ref sizes) => sizes.iter().sum(),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        match self.children {
+            Children::Leaf(ref values) => values.is_empty(),
+            Children::Internal(ref children, _) => children.is_empty(),
+        }
+    }
+}
+
+impl<V, S: SerdeInterface> BNode<V, S> {
+    /// An upper bound of the serialized size
+    fn bytes(&self) -> usize {
+        let leaf = matches!(self.children, Children::Leaf(_));
+        let keys: usize = self.keys.iter().map(|k| entry_bytes::<S>(k, leaf)).sum();
+        if leaf {
+            NODE_OVERHEAD + keys
+        } else {
+            // the first child has no key
+            NODE_OVERHEAD + keys + 2 * NUMBER_BYTES
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct KeyRef<'a>(#[serde(with = "compact_bytes")] &'a [u8]);
+
+// counts the bytes written to it
+struct ByteCounter(usize);
+
+impl Write for ByteCounter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// the serialized bytes of an entry: its key, as `S` writes it, and the
+// address of its value, or the address and the size of its child
+fn entry_bytes<S: SerdeInterface>(key: &[u8], leaf: bool) -> usize {
+    let mut counter = ByteCounter(0);
+    // neither the key nor the counter can fail
+    S::to_writer(&mut counter, &KeyRef(key)).unwrap();
+    let numbers = if leaf { 1 } else { 2 };
+    counter.0 + 1 + numbers * NUMBER_BYTES
+}
+
+impl<V: Agent, S: SerdeInterface> From<BNodeHD> for BNode<V, S> {
+    fn from(nodehd: BNodeHD) -> Self {
+        match nodehd {
+            BNodeHD::Leaf { keys, value_addrs } => {
+                let values = value_addrs
+                    .into_iter()
+                    .map(|addr| rc!(V::new(None, Some(addr))))
+                    .collect();
+                BNode::leaf(keys, values)
+            }
+            BNodeHD::Internal {
+                keys,
+                child_addrs,
+                sizes,
+            } => {
+                let children = child_addrs
+                    .into_iter()
+                    .map(|addr| rc!(BNodeAgent::new(None, Some(addr))))
+                    .collect();
+                BNode {
+                    keys,
+                    children: Children::Internal(children, sizes),
+                }
+            }
+        }
+    }
+}
+
+impl<V: Agent, S: SerdeInterface> From<&BNode<V, S>> for BNodeHD {
+    fn from(node: &BNode<V, S>) -> BNodeHD {
+        let keys = node.keys.clone();
+        match node.children {
+            Children::Leaf(ref values) => BNodeHD::Leaf {
+                keys,
+                value_addrs: values.iter().map(|v| v.borrow().addr().unwrap()).collect(),
+            },
+            Children::Internal(ref children, ref sizes) => BNodeHD::Internal {
+                keys,
+                child_addrs: children
+                    .iter()
+                    .map(|c| c.borrow().addr().unwrap())
+                    .collect(),
+                sizes: sizes.clone(),
+            },
+        }
+    }
+}
+
+/// BNodeAgent works for BNode
+///
+/// `S`: how to serialize / deserialize data
+///
+/// `V`: the agent of values
+struct BNodeAgent<V, S = SerdeJson> {
+    inner: Option<BNode<V, S>>,
+    addr: Option<u64>,
+    // got since the last unload, so stored nodes under it may be loaded
+    visited: bool,
+    format: PhantomData<S>,
+}
+
+impl<V: Agent, S: SerdeInterface> Agent for BNodeAgent<V, S> {
+    type Inner = BNode<V, S>;
+    fn new(inner: Option<Self::Inner>, addr: Option<u64>) -> Self {
+        BNodeAgent {
+            visited: inner.is_some(),
+            inner,
+            addr,
+            format: PhantomData,
+        }
+    }
+
+    fn addr(&self) -> Option<u64> {
+        self.addr
+    }
+
+    fn get_mut(&mut self, storage: &mut impl Storage) -> Result<Option<&mut Self::Inner>> {
+        self.get(storage)?;
+        Ok(self.inner.as_mut())
+    }
+
+    fn get(&mut self, storage: &mut impl Storage) -> Result<Option<&Self::Inner>> {
+        self.visited = true;
+        if let (None, Some(addr)) = (&self.inner, self.addr) {
+            let nodehd: BNodeHD = storage.read_node::<S, _>(addr)?;
+            self.inner = Some(nodehd.into());
+            debug!("[Agent] loads a BNode from disk");
+        }
+        Ok(self.inner.as_ref())
+    }
+
+    fn store(&mut self, storage: &mut impl Storage) -> Result<()> {
+        if let (Some(node), None) = (&self.inner, self.addr) {
+            match node.children {
+                Children::Leaf(ref values) => {
+                    for value in values {
+                        value.borrow_mut().store(storage)?;
+                    }
+                }
+                Children::Internal(ref children, _) => {
+                    for child in children {
+                        child.borrow_mut().store(storage)?;
+                    }
+                }
+            }
+            let nodehd: BNodeHD = node.into();
+            debug!("[Agent] writes down a BNode with {} keys", node.keys.len());
+            self.addr = Some(storage.write_record::<S, _>(&nodehd)?);
+        }
+        Ok(())
+    }
+
+    fn unload(&mut self) {
+        #[cfg(test)]
+        crate::logical_tree::UNLOAD_VISITS.with(|visits| visits.set(visits.get() + 1));
+        match (&self.inner, self.addr) {
+            (Some(_), Some(_)) => self.inner = None,
+            // a new node may refer to stored ones, which are only loaded
+            // through it, so a new node not visited has none loaded
+            (Some(node), None) if self.visited => {
+                self.visited = false;
+                match node.children {
+                    Children::Leaf(ref values) => {
+                        for value in values {
+                            value.borrow_mut().unload();
+                        }
+                    }
+                    Children::Internal(ref children, _) => {
+                        for child in children {
+                            child.borrow_mut().unload();
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn copy(addr: u64, from: &mut impl Storage, to: &mut impl Storage) -> Result<u64> {
+        let mut nodehd: BNodeHD = from.read_record::<S, _>(addr)?;
+        match nodehd {
+            BNodeHD::Leaf {
+                ref mut value_addrs,
+                ..
+            } => {
+                for value_addr in value_addrs.iter_mut() {
+                    *value_addr = V::copy(*value_addr, from, to)?;
+                }
+            }
+            BNodeHD::Internal {
+                ref mut child_addrs,
+                ..
+            } => {
+                for child_addr in child_addrs.iter_mut() {
+                    *child_addr = Self::copy(*child_addr, from, to)?;
+                }
+            }
+        }
+        to.write_record::<S, _>(&nodehd)
+    }
+}
+
+/// A B+tree with byte keys.
+///
+/// `S`: how to serialize / deserialize tree nodes and values
+///
+/// `PAGE`: nodes are split once their serialized size, as `S` writes their
+/// keys, may exceed `PAGE` bytes, and merged with a sibling once they shrink
+/// below a quarter of it. A node holding a single huge key may still exceed
+/// `PAGE`.
+///
+/// `V`: the agent of values, like `StringAgent`, `BytesAgent` or `SerdeAgent`
+///
+/// `C`: the order of keys
+pub struct BTree<
+    S = SerdeJson,
+    const PAGE: usize = DEFAULT_PAGE_SIZE,
+    V = StringAgent<S>,
+    C = Bytewise,
+> {
+    root: Option<BNodeAgentCell<V, S>>,
+    order: PhantomData<C>,
+}
+
+impl<S, const PAGE: usize, V, C> BTree<S, PAGE, V, C>
+where
+    S: SerdeInterface,
+    V: Agent,
+    C: Comparator,
+{
+    fn _node(agent: &BNodeAgentCell<V, S>, storage: &mut impl Storage) -> Result<BNode<V, S>> {
+        let mut agent = agent.borrow_mut();
+        Ok(agent.get(storage)?.unwrap().clone())
+    }
+
+    fn _make(node: BNode<V, S>) -> BNodeAgentCell<V, S> {
+        rc!(BNodeAgent::new(Some(node), None))
+    }
+
+    // split a node in two halves of similar bytes if it outgrows a page,
+    // return the separator key with the right half
+    fn _split(mut node: BNode<V, S>) -> SplitResult<V, S> {
+        let bytes = node.bytes();
+        if bytes <= PAGE {
+            return (node, None);
+        }
+        // the index of the first key whose prefix passes half the bytes
+        let leaf = matches!(node.children, Children::Leaf(_));
+        let mut prefix = NODE_OVERHEAD;
+        let half = node
+            .keys
+            .iter()
+            .position(|k| {
+                prefix += entry_bytes::<S>(k, leaf);
+                prefix * 2 >= bytes
+            })
+            .unwrap_or(0);
+        let len = node.keys.len();
+        match node.children {
+            Children::Leaf(ref mut values) if len >= 2 => {
+                let at = half.max(1);
+                let right_keys = node.keys.split_off(at);
+                let right_values = values.split_off(at);
+                let separator = right_keys[0].clone();
+                debug!("[_split] split a leaf at {:?}", separator);
+                (
+                    node,
+                    Some((separator, BNode::leaf(right_keys, right_values))),
+                )
+            }
+            Children::Internal(ref mut children, ref mut sizes) if len >= 3 => {
+                // keys[at] goes up, each half keeps at least two children
+                let at = half.max(1).min(len - 2);
+                let right_keys = node.keys.split_off(at + 1);
+                let separator = node.keys.pop().unwrap();
+                let right_children = children.split_off(at + 1);
+                let right_sizes = sizes.split_off(at + 1);
+                debug!("[_split] split an internal node at {:?}", separator);
+                let right = BNode {
+                    keys: right_keys,
+                    children: Children::Internal(right_children, right_sizes),
+                };
+                (node, Some((separator, right)))
+            }
+            _ => (node, None),
+        }
+    }
+
+    // merge two neighbor nodes, `separator` is the key between them
+    fn _merge(mut left: BNode<V, S>, separator: Vec<u8>, right: BNode<V, S>) -> BNode<V, S> {
+        match (&mut left.children, right.children) {
+            (Children::Leaf(ref mut values), Children::Leaf(right_values)) => {
+                values.extend(right_values);
+            }
+            (
+                Children::Internal(ref mut children, ref mut sizes),
+                Children::Internal(right_children, right_sizes),
+            ) => {
+                left.keys.push(separator);
+                children.extend(right_children);
+                sizes.extend(right_sizes);
+            }
+            _ => unreachable!("all leaves are at the same depth"),
+        }
+        left.keys.extend(right.keys);
+        left
+    }
+
+    // replace children[at] with a node and maybe its split right half
+    fn _put_child(node: &mut BNode<V, S>, at: usize, child: BNode<V, S>) {
+        let (left, right) = Self::_split(child);
+        if let Children::Internal(ref mut children, ref mut sizes) = node.children {
+            sizes[at] = left.size();
+            children[at] = Self::_make(left);
+            if let Some((separator, right)) = right {
+                node.keys.insert(at, separator);
+                sizes.insert(at + 1, right.size());
+                children.insert(at + 1, Self::_make(right));
+            }
+        }
+    }
+
+    // split items of `costs` bytes into runs filling a page each, with at
+    // least `min` items in every run if there is more than one
+    fn _pack(costs: &[usize], min: usize) -> Vec<std::ops::Range<usize>> {
+        let mut runs = vec![];
+        let (mut start, mut bytes) = (0, NODE_OVERHEAD);
+        for (i, cost) in costs.iter().enumerate() {
+            if i > start && bytes + cost > PAGE {
+                runs.push(start..i);
+                start = i;
+                bytes = NODE_OVERHEAD;
+            }
+            bytes += cost;
+        }
+        if start < costs.len() {
+            runs.push(start..costs.len());
+        }
+        // the last run may be too short, take an item of the previous one,
+        // or join it
+        if runs.len() >= 2 && runs[runs.len() - 1].len() < min {
+            let last = runs.pop().unwrap();
+            let previous = runs.pop().unwrap();
+            if previous.len() > min {
+                runs.push(previous.start..previous.end - 1);
+                runs.push(previous.end - 1..last.end);
+            } else {
+                runs.push(previous.start..last.end);
+            }
+        }
+        runs
+    }
+
+    fn _find(&self, key: &[u8], storage: &mut impl Storage) -> Result<Option<ValueAgentCell<V>>> {
+        let mut agent = match self.root {
+            Some(ref root) => root.clone(),
+            None => return Ok(None),
+        };
+        loop {
+            let next = {
+                let mut ag = agent.borrow_mut();
+                let node = ag.get(storage)?.unwrap();
+                match node.children {
+                    Children::Leaf(ref values) => {
+                        return Ok(node
+                            .keys
+                            .binary_search_by(|k| C::compare(k, key))
+                            .ok()
+                            .map(|i| values[i].clone()));
+                    }
+                    Children::Internal(ref children, _) => {
+                        let i = node
+                            .keys
+                            .partition_point(|k| C::compare(k, key) != Ordering::Greater);
+                        children[i].clone()
+                    }
+                }
+            };
+            agent = next;
+        }
+    }
+
+    fn _insert(
+        &mut self,
+        key: Vec<u8>,
+        value: V::Inner,
+        agent: &BNodeAgentCell<V, S>,
+        storage: &mut impl Storage,
+    ) -> Result<BNode<V, S>> {
+        let mut node = Self::_node(agent, storage)?;
+        let child = match node.children {
+            Children::Leaf(ref mut values) => {
+                let value = rc!(V::new(Some(value), None));
+                match node.keys.binary_search_by(|k| C::compare(k, &key)) {
+                    Ok(i) => values[i] = value,
+                    Err(i) => {
+                        node.keys.insert(i, key);
+                        values.insert(i, value);
+                    }
+                }
+                return Ok(node);
+            }
+            Children::Internal(ref children, _) => {
+                let i = node
+                    .keys
+                    .partition_point(|k| C::compare(k, &key) != Ordering::Greater);
+                (i, children[i].clone())
+            }
+        };
+        let new_child = self._insert(key, value, &child.1, storage)?;
+        Self::_put_child(&mut node, child.0, new_child);
+        Ok(node)
+    }
+
+    // the key must be in the subtree of `agent`
+    fn _delete(
+        &mut self,
+        key: &[u8],
+        agent: &BNodeAgentCell<V, S>,
+        storage: &mut impl Storage,
+    ) -> Result<BNode<V, S>> {
+        let mut node = Self::_node(agent, storage)?;
+        let (at, child) = match node.children {
+            Children::Leaf(ref mut values) => {
+                let i = node.keys.binary_search_by(|k| C::compare(k, key)).unwrap();
+                node.keys.remove(i);
+                values.remove(i);
+                return Ok(node);
+            }
+            Children::Internal(ref children, _) => {
+                let i = node
+                    .keys
+                    .partition_point(|k| C::compare(k, key) != Ordering::Greater);
+                (i, children[i].clone())
+            }
+        };
+        let new_child = self._delete(key, &child, storage)?;
+        let underflow = match new_child.children {
+            Children::Leaf(_) => new_child.is_empty() || new_child.bytes() * 4 < PAGE,
+            Children::Internal(ref children, _) => {
+                children.len() < 2 || new_child.bytes() * 4 < PAGE
+            }
+        };
+        if !underflow {
+            Self::_put_child(&mut node, at, new_child);
+            return Ok(node);
+        }
+        // merge the child with a sibling, then split them again if needed
+        let (left_at, sibling_at) = if at > 0 { (at - 1, at - 1) } else { (0, 1) };
+        let sibling = match node.children {
+            Children::Internal(ref children, _) => children[sibling_at].clone(),
+            Children::Leaf(_) => unreachable!(),
+        };
+        let sibling = Self::_node(&sibling, storage)?;
+        let separator = node.keys.remove(left_at);
+        debug!("[_delete] merge nodes around {:?}", separator);
+        let merged = if at > 0 {
+            Self::_merge(sibling, separator, new_child)
+        } else {
+            Self::_merge(new_child, separator, sibling)
+        };
+        if let Children::Internal(ref mut children, ref mut sizes) = node.children {
+            children.remove(left_at + 1);
+            sizes.remove(left_at + 1);
+        }
+        Self::_put_child(&mut node, left_at, merged);
+        Ok(node)
+    }
+}
+
+impl<S, const PAGE: usize, V, C> DBTree for BTree<S, PAGE, V, C>
+where
+    S: SerdeInterface,
+    V: Agent,
+    C: Comparator,
+{
+    type Value = V::Inner;
+    type Comparator = C;
+
+    type Format = S;
+
+    fn new() -> Result<Self> {
+        Ok(BTree {
+            root: None,
+            order: PhantomData,
+        })
+    }
+
+    fn change_view(&mut self, addr: u64) -> Result<()> {
+        self.root = Some(rc!(BNodeAgent::new(None, Some(addr))));
+        Ok(())
+    }
+
+    fn store(&mut self, storage: &mut impl Storage) -> Result<Option<u64>> {
+        if let Some(ref root) = self.root {
+            root.borrow_mut().store(storage)?;
+            let addr = root.borrow().addr().unwrap();
+            Ok(Some(addr))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn find(&mut self, key: &[u8], storage: &mut impl Storage) -> Result<Option<Self::Value>> {
+        if let Some(agent) = self._find(key, storage)? {
+            return Ok(agent.borrow_mut().get(storage)?.cloned());
+        }
+        Ok(None)
+    }
+
+    fn insert(
+        &mut self,
+        key: Vec<u8>,
+        value: Self::Value,
+        storage: &mut impl Storage,
+    ) -> Result<()> {
+        let root = match self.root.as_ref().cloned() {
+            Some(root) => self._insert(key, value, &root, storage)?,
+            None => BNode::leaf(vec![key], vec![rc!(V::new(Some(value), None))]),
+        };
+        self.root = Some(match Self::_split(root) {
+            (root, None) => Self::_make(root),
+            (left, Some((separator, right))) => {
+                debug!("[insert] the tree grows higher");
+                let sizes = vec![left.size(), right.size()];
+                let children = vec![Self::_make(left), Self::_make(right)];
+                Self::_make(BNode {
+                    keys: vec![separator],
+                    children: Children::Internal(children, sizes),
+                })
+            }
+        });
+        Ok(())
+    }
+
+    fn delete(&mut self, key: &[u8], storage: &mut impl Storage) -> Result<()> {
+        if self._find(key, storage)?.is_none() {
+            return Ok(());
+        }
+        debug!("[delete] found key {:?}", key);
+        let root = self.root.as_ref().cloned().unwrap();
+        let mut root = self._delete(key, &root, storage)?;
+        // the tree shrinks when the root has a single child
+        loop {
+            let only_child = match root.children {
+                Children::Internal(ref children, _) if children.len() == 1 => children[0].clone(),
+                _ => break,
+            };
+            root = Self::_node(&only_child, storage)?;
+        }
+        self.root = if root.is_empty() {
+            None
+        } else {
+            Some(Self::_make(root))
+        };
+        Ok(())
+    }
+
+    fn unload(&mut self) {
+        if let Some(ref root) = self.root {
+            root.borrow_mut().unload();
+        }
+    }
+
+    fn bulk_load<I>(&mut self, pairs: I, storage: &mut impl Storage) -> Result<()>
+    where
+        I: Iterator<Item = (Vec<u8>, Self::Value)>,
+    {
+        let entries = store_sorted::<V, C, _>(pairs, storage)?;
+        // (smallest key, addr, size) of the nodes of a level, leaves first
+        let mut level = vec![];
+        let costs: Vec<usize> = entries
+            .iter()
+            .map(|(key, _)| entry_bytes::<S>(key, true))
+            .collect();
+        for run in Self::_pack(&costs, 1) {
+            let run = &entries[run];
+            let nodehd = BNodeHD::Leaf {
+                keys: run.iter().map(|(key, _)| key.clone()).collect(),
+                value_addrs: run.iter().map(|(_, addr)| *addr).collect(),
+            };
+            let addr = storage.write_record::<S, _>(&nodehd)?;
+            level.push((run[0].0.clone(), addr, run.len()));
+        }
+        while level.len() > 1 {
+            // a child costs its separator key, but the first one has none
+            let costs: Vec<usize> = level
+                .iter()
+                .map(|(key, _, _)| entry_bytes::<S>(key, false))
+                .collect();
+            let mut upper = vec![];
+            for run in Self::_pack(&costs, 2) {
+                let run = &level[run];
+                let nodehd = BNodeHD::Internal {
+                    keys: run[1..].iter().map(|(key, _, _)| key.clone()).collect(),
+                    child_addrs: run.iter().map(|(_, addr, _)| *addr).collect(),
+                    sizes: run.iter().map(|(_, _, size)| *size).collect(),
+                };
+                let addr = storage.write_record::<S, _>(&nodehd)?;
+                let size = run.iter().map(|(_, _, size)| size).sum();
+                upper.push((run[0].0.clone(), addr, size));
+            }
+            debug!("[bulk_load] build a level of {} nodes", upper.len());
+            level = upper;
+        }
+        self.root = level
+            .pop()
+            .map(|(_, addr, _)| rc!(BNodeAgent::new(None, Some(addr))));
+        Ok(())
+    }
+
+    fn len(&mut self, storage: &mut impl Storage) -> Result<usize> {
+        match self.root {
+            Some(ref root) => Ok(root.borrow_mut().get(storage)?.unwrap().size()),
+            None => Ok(0),
+        }
+    }
+
+    fn rank(&mut self, key: &[u8], inclusive: bool, storage: &mut impl Storage) -> Result<usize> {
+        let mut agent = match self.root {
+            Some(ref root) => root.clone(),
+            None => return Ok(0),
+        };
+        let mut rank = 0;
+        loop {
+            let next = {
+                let mut ag = agent.borrow_mut();
+                let node = ag.get(storage)?.unwrap();
+                match node.children {
+                    Children::Leaf(_) => {
+                        let below = node.keys.partition_point(|k| match C::compare(k, key) {
+                            Ordering::Less => true,
+                            Ordering::Equal => inclusive,
+                            Ordering::Greater => false,
+                        });
+                        return Ok(rank + below);
+                    }
+                    Children::Internal(ref children, ref sizes) => {
+                        let i = node
+                            .keys
+                            .partition_point(|k| C::compare(k, key) != Ordering::Greater);
+                        // every key of the children on the left is less
+                        rank += sizes[..i].iter().sum::<usize>();
+                        children[i].clone()
+                    }
+                }
+            };
+            agent = next;
+        }
+    }
+
+    fn select(&mut self, mut n: usize, storage: &mut impl Storage) -> Result<Option<Vec<u8>>> {
+        let mut agent = match self.root {
+            Some(ref root) => root.clone(),
+            None => return Ok(None),
+        };
+        loop {
+            let next = {
+                let mut ag = agent.borrow_mut();
+                let node = ag.get(storage)?.unwrap();
+                match node.children {
+                    Children::Leaf(_) => return Ok(node.keys.get(n).cloned()),
+                    Children::Internal(ref children, ref sizes) => {
+                        let mut next = None;
+                        for (child, size) in children.iter().zip(sizes.iter()) {
+                            if n < *size {
+                                next = Some(child.clone());
+                                break;
+                            }
+                            n -= size;
+                        }
+                        match next {
+                            Some(next) => next,
+                            None => return Ok(None),
+                        }
+                    }
+                }
+            };
+            agent = next;
+        }
+    }
+
+    fn copy(addr: u64, from: &mut impl Storage, to: &mut impl Storage) -> Result<u64> {
+        BNodeAgent::<V, S>::copy(addr, from, to)
+    }
+
+    type Cursor = BTreeCursor<V, S, C>;
+
+    fn range(&self, range: (Bound<Vec<u8>>, Bound<Vec<u8>>)) -> Self::Cursor {
+        BTreeCursor {
+            stack: vec![],
+            start: range.0,
+            end: range.1,
+            root: self.root.as_ref().cloned(),
+            order: PhantomData,
+        }
+    }
+}
+
+/// An in-order cursor over a `BTree`
+///
+/// Each item of `stack` is a node with the index of the next child (or
+/// value, for a leaf) to visit. `C` is the order of the tree.
+pub struct BTreeCursor<V, S, C = Bytewise> {
+    stack: Vec<(BNodeAgentCell<V, S>, usize)>,
+    start: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+    // the root we start from, the stack is filled at the first `next`
+    root: Option<BNodeAgentCell<V, S>>,
+    order: PhantomData<C>,
+}
+
+impl<V: Agent, S: SerdeInterface, C: Comparator> BTreeCursor<V, S, C> {
+    // push the path to the first key not before `start`
+    fn seek(&mut self, root: BNodeAgentCell<V, S>, storage: &mut impl Storage) -> Result<()> {
+        let mut agent = root;
+        loop {
+            let (index, next) = {
+                let mut ag = agent.borrow_mut();
+                let node = ag.get(storage)?.unwrap();
+                let keys = &node.keys;
+                match node.children {
+                    Children::Leaf(_) => {
+                        let index = match self.start {
+                            Bound::Included(ref s) => {
+                                keys.partition_point(|k| C::compare(k, s) == Ordering::Less)
+                            }
+                            Bound::Excluded(ref s) => {
+                                keys.partition_point(|k| C::compare(k, s) != Ordering::Greater)
+                            }
+                            Bound::Unbounded => 0,
+                        };
+                        (index, None)
+                    }
+                    Children::Internal(ref children, _) => {
+                        let index = match self.start {
+                            Bound::Included(ref s) | Bound::Excluded(ref s) => {
+                                keys.partition_point(|k| C::compare(k, s) != Ordering::Greater)
+                            }
+                            Bound::Unbounded => 0,
+                        };
+                        (index + 1, Some(children[index].clone()))
+                    }
+                }
+            };
+            self.stack.push((agent, index));
+            match next {
+                Some(next) => agent = next,
+                None => return Ok(()),
+            }
+        }
+    }
+
+    fn after_end(&self, key: &[u8]) -> bool {
+        match self.end {
+            Bound::Included(ref end) => C::compare(key, end) == Ordering::Greater,
+            Bound::Excluded(ref end) => C::compare(key, end) != Ordering::Less,
+            Bound::Unbounded => false,
+        }
+    }
+}
+
+impl<V: Agent, S: SerdeInterface, C: Comparator> Cursor for BTreeCursor<V, S, C> {
+    type Value = V::Inner;
+
+    fn next(&mut self, storage: &mut impl Storage) -> Result<Option<(Vec<u8>, V::Inner)>> {
+        if let Some(root) = self.root.take() {
+            self.seek(root, storage)?;
+        }
+        while let Some((agent, index)) = self.stack.pop() {
+            let step = {
+                let mut ag = agent.borrow_mut();
+                let node = ag.get(storage)?.unwrap();
+                match node.children {
+                    Children::Leaf(ref values) if index < values.len() => {
+                        Ok((node.keys[index].clone(), values[index].clone()))
+                    }
+                    Children::Internal(ref children, _) if index < children.len() => {
+                        Err(Some(children[index].clone()))
+                    }
+                    _ => Err(None),
+                }
+            };
+            match step {
+                Ok((key, value_agent)) => {
+                    if self.after_end(&key) {
+                        self.stack.clear();
+                        return Ok(None);
+                    }
+                    self.stack.push((agent, index + 1));
+                    let value = value_agent.borrow_mut().get(storage)?.cloned();
+                    return Ok(value.map(|value| (key, value)));
+                }
+                Err(Some(child)) => {
+                    self.stack.push((agent, index + 1));
+                    self.stack.push((child, 0));
+                }
+                // this node is exhausted
+                Err(None) => {}
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod btree_test {
+    use super::*;
+    use crate::logical_tree::LogicalTree;
+    use crate::storage::FileStorage;
+    use tempfile;
+
+    type SmallTree = BTree<SerdeJson, 256>;
+
+    // check keys, sizes and page bytes, return (depth, size). Stored nodes
+    // are encoded again, to check the estimate of their bytes.
+    fn check(
+        agent: &BNodeAgentCell<StringAgent, SerdeJson>,
+        is_root: bool,
+        storage: &mut FileStorage,
+    ) -> (usize, usize) {
+        let node = SmallTree::_node(agent, storage).unwrap();
+        assert!(node.keys.windows(2).all(|w| w[0] < w[1]));
+        assert!(node.bytes() <= 256);
+        if agent.borrow().addr().is_some() {
+            let mut record = vec![];
+            SerdeJson::to_writer(&mut record, &BNodeHD::from(&node)).unwrap();
+            assert!(record.len() <= node.bytes());
+        }
+        match node.children {
+            Children::Leaf(ref values) => {
+                assert_eq!(node.keys.len(), values.len());
+                assert!(is_root || !values.is_empty());
+                (1, values.len())
+            }
+            Children::Internal(ref children, ref sizes) => {
+                assert_eq!(node.keys.len() + 1, children.len());
+                assert!(children.len() >= 2);
+                let mut depth = None;
+                for (child, size) in children.iter().zip(sizes.iter()) {
+                    let (d, s) = check(child, false, storage);
+                    assert_eq!(*size, s);
+                    assert!(depth.is_none() || depth == Some(d));
+                    depth = Some(d);
+                }
+                (depth.unwrap() + 1, node.size())
+            }
+        }
+    }
+
+    #[test]
+    fn test_btree_sorted_insert() {
+        let path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+        let mut storage = FileStorage::new(&path).unwrap();
+        let mut tree = BTree::<SerdeJson>::new().unwrap();
+        for i in 0..10000 {
+            tree.insert(format!("{:05}", i).into(), i.to_string(), &mut storage)
+                .unwrap();
+        }
+        let addr = tree.store(&mut storage).unwrap().unwrap();
+
+        // a 4 KiB page holds about 140 keys, a few levels are enough
+        let mut tree = BTree::<SerdeJson>::new().unwrap();
+        tree.change_view(addr).unwrap();
+        let mut depth = 0;
+        let mut agent = tree.root.clone();
+        while let Some(current) = agent {
+            depth += 1;
+            let node = BTree::<SerdeJson>::_node(&current, &mut storage).unwrap();
+            agent = match node.children {
+                Children::Internal(ref children, _) => Some(children[0].clone()),
+                Children::Leaf(_) => None,
+            };
+        }
+        assert!(depth <= 3, "too deep: {}", depth);
+        assert_eq!(
+            Some("5678".to_owned()),
+            tree.find(b"05678", &mut storage).unwrap()
+        );
+        assert_eq!(None, tree.find(b"5678", &mut storage).unwrap());
+    }
+
+    #[test]
+    fn test_btree_delete() {
+        let path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+        let mut storage = FileStorage::new(&path).unwrap();
+        let mut tree = SmallTree::new().unwrap();
+        for i in 0..500 {
+            tree.insert(
+                format!("{:03}", (i * 7) % 500).into(),
+                i.to_string(),
+                &mut storage,
+            )
+            .unwrap();
+        }
+        tree.store(&mut storage).unwrap();
+        let (depth, size) = check(tree.root.as_ref().unwrap(), true, &mut storage);
+        assert!(depth >= 3);
+        assert_eq!(500, size);
+
+        for i in (0..500).filter(|i| i % 5 != 0) {
+            tree.delete(format!("{:03}", i).as_bytes(), &mut storage)
+                .unwrap();
+        }
+        tree.delete(b"nothing", &mut storage).unwrap();
+        tree.store(&mut storage).unwrap();
+        let (_, size) = check(tree.root.as_ref().unwrap(), true, &mut storage);
+        assert_eq!(100, size);
+        assert_eq!(None, tree.find(b"001", &mut storage).unwrap());
+        assert!(tree.find(b"005", &mut storage).unwrap().is_some());
+
+        for i in (0..500).filter(|i| i % 5 == 0) {
+            tree.delete(format!("{:03}", i).as_bytes(), &mut storage)
+                .unwrap();
+        }
+        assert!(tree.root.is_none());
+    }
+
+    #[test]
+    fn test_btree_logical_tree_range() {
+        let path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+        let mut tree = LogicalTree::<SmallTree>::new(&path).unwrap();
+        tree.begin().unwrap();
+        for i in 0..100 {
+            tree.put(format!("{:03}", i), i.to_string()).unwrap();
+        }
+        tree.del("050").unwrap();
+        tree.commit().unwrap();
+        drop(tree);
+
+        let mut tree = LogicalTree::<SmallTree>::new(&path).unwrap();
+        assert_eq!(Some("42".to_owned()), tree.get("042").unwrap());
+        assert_eq!(99, tree.iter().unwrap().count());
+        let keys: Vec<String> = tree
+            .range("047".."053")
+            .unwrap()
+            .map(|pair| String::from_utf8(pair.unwrap().0).unwrap())
+            .collect();
+        assert_eq!(vec!["047", "048", "049", "051", "052"], keys);
+        let keys: Vec<String> = tree
+            .range("0975"..)
+            .unwrap()
+            .map(|pair| String::from_utf8(pair.unwrap().0).unwrap())
+            .collect();
+        assert_eq!(vec!["098", "099"], keys);
+
+        tree.compact().unwrap();
+        assert_eq!(Some("42".to_owned()), tree.get("042").unwrap());
+        assert_eq!(99, tree.iter().unwrap().count());
+    }
+
+    #[test]
+    fn test_btree_comparator() {
+        use crate::comparator::{Numeric, Reversed};
+        type Tree = BTree<SerdeJson, 256, StringAgent, Reversed<Numeric>>;
+        let path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+        let mut tree = LogicalTree::<Tree>::new(&path).unwrap();
+        tree.begin().unwrap();
+        for i in 0..300 {
+            tree.put(format!("k{}", (i * 7) % 300), i.to_string())
+                .unwrap();
+        }
+        tree.del("k150").unwrap();
+        tree.commit().unwrap();
+
+        let keys: Vec<String> = tree
+            .iter()
+            .unwrap()
+            .map(|pair| String::from_utf8(pair.unwrap().0).unwrap())
+            .collect();
+        let expected: Vec<String> = (0..300)
+            .rev()
+            .filter(|i| *i != 150)
+            .map(|i| format!("k{}", i))
+            .collect();
+        assert_eq!(expected, keys);
+        let keys: Vec<String> = tree
+            .range("k152".."k148")
+            .unwrap()
+            .map(|pair| String::from_utf8(pair.unwrap().0).unwrap())
+            .collect();
+        assert_eq!(vec!["k152", "k151", "k149"], keys);
+        assert_eq!(Some("1".to_owned()), tree.get("k7").unwrap());
+    }
+
+    #[test]
+    fn test_btree_bulk_load() {
+        let path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+        let mut storage = FileStorage::new(&path).unwrap();
+        let mut tree = SmallTree::new().unwrap();
+        let pairs = (0..2000).map(|i| (format!("{:04}", i).into_bytes(), i.to_string()));
+        tree.bulk_load(pairs, &mut storage).unwrap();
+        let (_, size) = check(tree.root.as_ref().unwrap(), true, &mut storage);
+        assert_eq!(2000, size);
+        assert_eq!(Some(1234), tree.rank(b"1234", false, &mut storage).ok());
+
+        // it goes on like any other tree
+        tree.insert(b"12345".to_vec(), "new".to_owned(), &mut storage)
+            .unwrap();
+        for i in 0..1000 {
+            tree.delete(format!("{:04}", i).as_bytes(), &mut storage)
+                .unwrap();
+        }
+        tree.store(&mut storage).unwrap();
+        let (_, size) = check(tree.root.as_ref().unwrap(), true, &mut storage);
+        assert_eq!(1001, size);
+        assert_eq!(
+            Some("new".to_owned()),
+            tree.find(b"12345", &mut storage).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_btree_page_bytes() {
+        let path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+        let mut storage = FileStorage::new(&path).unwrap();
+        let mut tree = SmallTree::new().unwrap();
+        // invalid UTF-8, json writes them as hex, twice as long
+        for i in 0..300u32 {
+            let mut key = vec![0xff];
+            key.extend_from_slice(&(i * 7919 % 300).to_be_bytes());
+            tree.insert(key, u64::MAX.to_string(), &mut storage)
+                .unwrap();
+        }
+        tree.store(&mut storage).unwrap();
+        let (depth, size) = check(tree.root.as_ref().unwrap(), true, &mut storage);
+        assert!(depth >= 3);
+        assert_eq!(300, size);
+    }
+}
@@ -0,0 +1,212 @@
+//! A bounded cache of tree nodes.
+//!
+//! Nodes are immutable once stored, so a node is known by its address. Every
+//! storage handle keeps a `NodeCache`, shared by all the views of the tree
+//! opened on it. Trees drop the nodes they loaded after every operation (see
+//! `Agent::unload`), and find the hot ones here next time, so the memory
+//! kept for clean nodes is bounded by the budget of the cache. Every handle
+//! has a cache of its own, so a `SharedDb` keeps one in each thread.
+
+use std::any::Any;
+use std::collections::{BTreeMap, HashMap};
+use std::mem;
+
+use log::debug;
+
+/// The default budget of a `NodeCache`, 8 MiB
+pub const DEFAULT_CACHE_BUDGET: usize = 8 << 20;
+
+// the bookkeeping of an entry: the hash map and LRU entries, and the box
+const ENTRY_OVERHEAD: usize = 96;
+
+/// A decoded node, which knows how much memory it takes in a `NodeCache`
+pub trait HeapSize {
+    /// The bytes it owns on the heap, besides `size_of::<Self>()`
+    fn heap_size(&self) -> usize;
+}
+
+/// The bytes charged to the budget of a `NodeCache` for keeping `node`
+pub fn cache_size<T: HeapSize>(node: &T) -> usize {
+    mem::size_of::<T>() + node.heap_size() + ENTRY_OVERHEAD
+}
+
+struct Entry {
+    node: Box<dyn Any + Send + Sync>,
+    bytes: usize,
+    tick: u64,
+}
+
+/// An address-keyed LRU cache of decoded nodes, holding at most `budget`
+/// bytes of them. The size of a node is the memory it takes once decoded,
+/// see `cache_size`.
+pub struct NodeCache {
+    budget: usize,
+    used: usize,
+    // increases with every access, to order the entries
+    tick: u64,
+    entries: HashMap<u64, Entry>,
+    // tick -> addr, the least recently used first
+    lru: BTreeMap<u64, u64>,
+    hits: u64,
+    misses: u64,
+}
+
+impl NodeCache {
+    /// Create an empty cache. A budget of 0 disables it.
+    pub fn new(budget: usize) -> Self {
+        NodeCache {
+            budget,
+            used: 0,
+            tick: 0,
+            entries: HashMap::new(),
+            lru: BTreeMap::new(),
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    /// The most bytes of nodes kept
+    pub fn budget(&self) -> usize {
+        self.budget
+    }
+
+    /// Change the budget, evicting nodes if it shrinks
+    pub fn set_budget(&mut self, budget: usize) {
+        self.budget = budget;
+        self.evict(0);
+    }
+
+    /// The bytes of nodes kept now
+    pub fn used(&self) -> usize {
+        self.used
+    }
+
+    /// The number of nodes kept now
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Whether no node is kept
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// The number of lookups which found their node
+    pub fn hits(&self) -> u64 {
+        self.hits
+    }
+
+    /// The number of lookups which didn't find their node
+    pub fn misses(&self) -> u64 {
+        self.misses
+    }
+
+    /// Get a copy of the node at `addr`, marking it as recently used
+    pub fn get<T: Clone + 'static>(&mut self, addr: u64) -> Option<T> {
+        let node = match self.entries.get_mut(&addr) {
+            Some(entry) => match entry.node.downcast_ref::<T>() {
+                Some(node) => {
+                    self.lru.remove(&entry.tick);
+                    self.tick += 1;
+                    entry.tick = self.tick;
+                    self.lru.insert(self.tick, addr);
+                    Some(node.clone())
+                }
+                None => None,
+            },
+            None => None,
+        };
+        match node {
+            Some(_) => self.hits += 1,
+            None => self.misses += 1,
+        }
+        node
+    }
+
+    /// Keep the node at `addr`, evicting the least recently used ones to
+    /// make room. A node larger than the budget isn't kept.
+    pub fn insert<T: Send + Sync + 'static>(&mut self, addr: u64, node: T, bytes: usize) {
+        self.remove(addr);
+        if bytes > self.budget {
+            return;
+        }
+        self.evict(bytes);
+        self.tick += 1;
+        self.lru.insert(self.tick, addr);
+        self.entries.insert(
+            addr,
+            Entry {
+                node: Box::new(node),
+                bytes,
+                tick: self.tick,
+            },
+        );
+        self.used += bytes;
+    }
+
+    /// Forget every node, e.g. when the addresses now lead to another file
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.lru.clear();
+        self.used = 0;
+    }
+
+    fn remove(&mut self, addr: u64) {
+        if let Some(entry) = self.entries.remove(&addr) {
+            self.lru.remove(&entry.tick);
+            self.used -= entry.bytes;
+        }
+    }
+
+    // evict the least recently used nodes until `bytes` more fit
+    fn evict(&mut self, bytes: usize) {
+        while self.used + bytes > self.budget {
+            let addr = match self.lru.values().next() {
+                Some(&addr) => addr,
+                None => break,
+            };
+            debug!("[NodeCache] evicts the node at addr {}", addr);
+            self.remove(addr);
+        }
+    }
+}
+
+impl Default for NodeCache {
+    fn default() -> Self {
+        Self::new(DEFAULT_CACHE_BUDGET)
+    }
+}
+
+#[cfg(test)]
+mod cache_test {
+    use super::NodeCache;
+
+    #[test]
+    fn test_node_cache_lru() {
+        let mut cache = NodeCache::new(30);
+        cache.insert(1, "one".to_owned(), 10);
+        cache.insert(2, "two".to_owned(), 10);
+        cache.insert(3, "three".to_owned(), 10);
+        assert_eq!(30, cache.used());
+        // 1 is used recently, so 2 is evicted
+        assert_eq!(Some("one".to_owned()), cache.get::<String>(1));
+        cache.insert(4, "four".to_owned(), 10);
+        assert_eq!(None, cache.get::<String>(2));
+        assert_eq!(Some("three".to_owned()), cache.get::<String>(3));
+        // a wrong type is a miss
+        assert_eq!(None, cache.get::<u64>(4));
+        assert_eq!((2, 2), (cache.hits(), cache.misses()));
+
+        // too large to be kept
+        cache.insert(5, "five".to_owned(), 31);
+        assert_eq!(None, cache.get::<String>(5));
+        assert_eq!(3, cache.len());
+
+        cache.set_budget(15);
+        assert_eq!(1, cache.len());
+        assert_eq!(Some("three".to_owned()), cache.get::<String>(3));
+        cache.clear();
+        assert!(cache.is_empty());
+        assert_eq!(0, cache.used());
+    }
+}
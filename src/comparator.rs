@@ -0,0 +1,157 @@
+//! Choose the order of keys in a tree.
+//!
+//! A tree takes a `Comparator` as a type parameter, `Bytewise` by default.
+//! The name of the comparator is recorded in the storage, and opening it
+//! with another comparator fails, since the tree can't be searched in an
+//! order it wasn't built in.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use dbdb::{BinaryTree, LogicalTree, StringAgent};
+//! use dbdb::comparator::Numeric;
+//! use dbdb::serde_interface::SerdeJson;
+//!
+//! let mut tree = LogicalTree::<BinaryTree<SerdeJson, StringAgent, Numeric>>::new("some.db")?;
+//! tree.put("file10", "b".to_owned())?;
+//! tree.put("file2", "a".to_owned())?;
+//! // file2, file10
+//! for pair in tree.iter()? {
+//!     let (key, value) = pair?;
+//! }
+//! ```
+
+use std::cmp::Ordering;
+use std::marker::PhantomData;
+
+/// An order of keys. It has no fields, use it as a `PhantomData`.
+pub trait Comparator {
+    /// The name recorded in the storage. Two comparators with the same name
+    /// must order keys the same way.
+    fn name() -> String;
+
+    /// Compare two keys. Keys comparing `Equal` are the same key.
+    fn compare(a: &[u8], b: &[u8]) -> Ordering;
+}
+
+/// Lexicographic order of bytes, the default one
+pub struct Bytewise;
+
+impl Comparator for Bytewise {
+    fn name() -> String {
+        "bytewise".to_owned()
+    }
+
+    fn compare(a: &[u8], b: &[u8]) -> Ordering {
+        a.cmp(b)
+    }
+}
+
+/// Like `Bytewise`, but ASCII letters are compared regardless of case, so
+/// that `"Key"` and `"key"` are the same key
+pub struct CaseInsensitive;
+
+impl Comparator for CaseInsensitive {
+    fn name() -> String {
+        "case-insensitive".to_owned()
+    }
+
+    fn compare(a: &[u8], b: &[u8]) -> Ordering {
+        let a = a.iter().map(u8::to_ascii_lowercase);
+        a.cmp(b.iter().map(u8::to_ascii_lowercase))
+    }
+}
+
+/// Like `Bytewise`, but runs of ASCII digits are compared by their value, so
+/// that `"file2"` comes before `"file10"`
+pub struct Numeric;
+
+// strip the leading zeros of a run of digits
+fn significant(digits: &[u8]) -> &[u8] {
+    let zeros = digits.iter().take_while(|&&d| d == b'0').count();
+    &digits[zeros..]
+}
+
+impl Comparator for Numeric {
+    fn name() -> String {
+        "numeric".to_owned()
+    }
+
+    fn compare(a: &[u8], b: &[u8]) -> Ordering {
+        let (mut i, mut j) = (0, 0);
+        while i < a.len() && j < b.len() {
+            if a[i].is_ascii_digit() && b[j].is_ascii_digit() {
+                let (start_i, start_j) = (i, j);
+                while i < a.len() && a[i].is_ascii_digit() {
+                    i += 1;
+                }
+                while j < b.len() && b[j].is_ascii_digit() {
+                    j += 1;
+                }
+                let (x, y) = (significant(&a[start_i..i]), significant(&b[start_j..j]));
+                // a longer number is a greater one
+                let order = x.len().cmp(&y.len()).then_with(|| x.cmp(y));
+                if order != Ordering::Equal {
+                    return order;
+                }
+            } else {
+                if a[i] != b[j] {
+                    return a[i].cmp(&b[j]);
+                }
+                i += 1;
+                j += 1;
+            }
+        }
+        // "1" and "01" are equal numbers, but different keys
+        (a.len() - i).cmp(&(b.len() - j)).then_with(|| a.cmp(b))
+    }
+}
+
+/// The reverse order of `C`
+pub struct Reversed<C = Bytewise> {
+    order: PhantomData<C>,
+}
+
+impl<C: Comparator> Comparator for Reversed<C> {
+    fn name() -> String {
+        format!("reversed({})", C::name())
+    }
+
+    fn compare(a: &[u8], b: &[u8]) -> Ordering {
+        C::compare(a, b).reverse()
+    }
+}
+
+#[cfg(test)]
+mod comparator_test {
+    use super::*;
+
+    fn sorted<C: Comparator>(keys: &[&str]) -> Vec<String> {
+        let mut keys: Vec<&str> = keys.to_vec();
+        keys.sort_by(|a, b| C::compare(a.as_bytes(), b.as_bytes()));
+        keys.into_iter().map(str::to_owned).collect()
+    }
+
+    #[test]
+    fn test_comparators() {
+        let keys = ["file10", "File3", "file2", "file02", "a"];
+        assert_eq!(
+            vec!["File3", "a", "file02", "file10", "file2"],
+            sorted::<Bytewise>(&keys)
+        );
+        assert_eq!(
+            vec!["File3", "a", "file02", "file2", "file10"],
+            sorted::<Numeric>(&keys)
+        );
+        assert_eq!(
+            vec!["a", "file02", "file10", "file2", "File3"],
+            sorted::<CaseInsensitive>(&keys)
+        );
+        assert_eq!(
+            vec!["file2", "file10", "file02", "a", "File3"],
+            sorted::<Reversed>(&keys)
+        );
+        assert_eq!(Ordering::Equal, CaseInsensitive::compare(b"Key", b"kEY"));
+        assert_eq!("reversed(numeric)", Reversed::<Numeric>::name());
+    }
+}
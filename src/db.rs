@@ -0,0 +1,339 @@
+//! Public entry point of the database.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use dbdb::{Db, LockPolicy, OpenOptions};
+//! use dbdb::serde_interface::SerdeBincode;
+//!
+//! let mut db = OpenOptions::new()
+//!     .format::<SerdeBincode>()
+//!     .create(true)
+//!     .lock_policy(LockPolicy::NoWait)
+//!     .open("some.db")?;
+//! db.put("answer".to_owned(), "42".to_owned())?;
+//! assert_eq!(Some("42".to_owned()), db.get("answer")?);
+//! ```
+
+use std::marker::PhantomData;
+use std::path::Path;
+
+use anyhow::Result;
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::avl_tree::AvlTree;
+use crate::btree::BTree;
+use crate::cache::DEFAULT_CACHE_BUDGET;
+use crate::comparator::Comparator;
+use crate::logical_tree::{Agent, BinaryTree, BytesAgent, DBTree, LogicalTree, SerdeAgent};
+use crate::rb_tree::RedBlackTree;
+use crate::serde_interface::{SerdeInterface, SerdeJson};
+use crate::shared::SharedDb;
+use crate::storage::{FileStorage, SyncMode};
+
+/// A database handle.
+///
+/// `Db` is a `LogicalTree` over a concrete `DBTree`. Get one from
+/// `OpenOptions::open` or `LogicalTree::new`.
+pub type Db<T = BinaryTree> = LogicalTree<T>;
+
+/// What to do when `begin` finds the file locked by another writer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockPolicy {
+    /// Block until the other writer commits
+    Wait,
+    /// Return a `LockedError` at once
+    NoWait,
+}
+
+/// A kind of `DBTree` which can be built with any serde format `F`, so that
+/// `OpenOptions` is able to choose the tree and the format separately.
+pub trait WithFormat<F> {
+    /// The same kind of tree, using the format `F`
+    type Tree: DBTree;
+}
+
+/// A kind of value `Agent` which can be built with any serde format `F`
+pub trait AgentWithFormat<F> {
+    /// The same kind of agent, using the format `F`
+    type Agent: Agent;
+}
+
+impl<T, S, F> AgentWithFormat<F> for SerdeAgent<T, S>
+where
+    T: Serialize + DeserializeOwned + Clone,
+    F: SerdeInterface,
+{
+    type Agent = SerdeAgent<T, F>;
+}
+
+impl<S, F: SerdeInterface> AgentWithFormat<F> for BytesAgent<S> {
+    type Agent = BytesAgent<F>;
+}
+
+impl<S, V, C, F> WithFormat<F> for BinaryTree<S, V, C>
+where
+    V: AgentWithFormat<F>,
+    C: Comparator,
+    F: SerdeInterface,
+{
+    type Tree = BinaryTree<F, V::Agent, C>;
+}
+
+impl<S, V, C, F> WithFormat<F> for AvlTree<S, V, C>
+where
+    V: AgentWithFormat<F>,
+    C: Comparator,
+    F: SerdeInterface,
+{
+    type Tree = AvlTree<F, V::Agent, C>;
+}
+
+impl<S, V, C, F> WithFormat<F> for RedBlackTree<S, V, C>
+where
+    V: AgentWithFormat<F>,
+    C: Comparator,
+    F: SerdeInterface,
+{
+    type Tree = RedBlackTree<F, V::Agent, C>;
+}
+
+impl<S, V, C, F, const PAGE: usize> WithFormat<F> for BTree<S, PAGE, V, C>
+where
+    V: AgentWithFormat<F>,
+    C: Comparator,
+    F: SerdeInterface,
+{
+    type Tree = BTree<F, PAGE, V::Agent, C>;
+}
+
+/// Options to configure how a database is opened, like `std::fs::OpenOptions`
+///
+/// `S`: how to serialize / deserialize tree nodes and values
+///
+/// `T`: which kind of `DBTree` organizes the data
+pub struct OpenOptions<S = SerdeJson, T = BinaryTree> {
+    create: bool,
+    lock_policy: LockPolicy,
+    sync: SyncMode,
+    cache_budget: usize,
+    format: PhantomData<S>,
+    tree: PhantomData<T>,
+}
+
+impl OpenOptions {
+    /// Create options with defaults: json format, create the file if it is
+    /// missing, wait for the write lock, fully sync every commit and cache
+    /// `DEFAULT_CACHE_BUDGET` bytes of nodes.
+    pub fn new() -> Self {
+        OpenOptions {
+            create: true,
+            lock_policy: LockPolicy::Wait,
+            sync: SyncMode::Full,
+            cache_budget: DEFAULT_CACHE_BUDGET,
+            format: PhantomData,
+            tree: PhantomData,
+        }
+    }
+}
+
+impl Default for OpenOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<S, T> OpenOptions<S, T>
+where
+    S: SerdeInterface,
+    T: WithFormat<S>,
+{
+    /// Choose the serde format. A file must always be opened with the format
+    /// it was written in.
+    pub fn format<F: SerdeInterface>(self) -> OpenOptions<F, T>
+    where
+        T: WithFormat<F>,
+    {
+        OpenOptions {
+            create: self.create,
+            lock_policy: self.lock_policy,
+            sync: self.sync,
+            cache_budget: self.cache_budget,
+            format: PhantomData,
+            tree: PhantomData,
+        }
+    }
+
+    /// Choose the kind of tree, e.g. `tree::<AvlTree>()`. Its own format
+    /// parameter, and the one of its value agent, are ignored, the one chosen
+    /// by `format` is used. A file must always be opened with the tree it
+    /// was written by, and with the same comparator.
+    pub fn tree<U: WithFormat<S>>(self) -> OpenOptions<S, U> {
+        OpenOptions {
+            create: self.create,
+            lock_policy: self.lock_policy,
+            sync: self.sync,
+            cache_budget: self.cache_budget,
+            format: PhantomData,
+            tree: PhantomData,
+        }
+    }
+
+    /// Create the file if it doesn't exist
+    pub fn create(mut self, create: bool) -> Self {
+        self.create = create;
+        self
+    }
+
+    /// Choose how a transaction waits for the write lock
+    pub fn lock_policy(mut self, lock_policy: LockPolicy) -> Self {
+        self.lock_policy = lock_policy;
+        self
+    }
+
+    /// Choose how commits are synced to disk
+    pub fn sync(mut self, sync: SyncMode) -> Self {
+        self.sync = sync;
+        self
+    }
+
+    /// Choose how many bytes of nodes each handle keeps in its node cache,
+    /// 0 to disable it. It's the memory of the decoded nodes, estimated by
+    /// `cache::cache_size`. Every handle has a budget of its own, and a
+    /// `SharedDb` opens a handle in each thread using it.
+    pub fn cache_budget(mut self, budget: usize) -> Self {
+        self.cache_budget = budget;
+        self
+    }
+
+    /// Open the database at `path` with these options
+    pub fn open<P: AsRef<Path>>(&self, path: P) -> Result<Db<T::Tree>> {
+        open_file(
+            path,
+            self.create,
+            self.sync,
+            self.cache_budget,
+            self.lock_policy,
+        )
+    }
+
+    /// Open the database at `path` with these options, as a handle which
+    /// can be shared between threads
+    pub fn open_shared<P: AsRef<Path>>(&self, path: P) -> Result<SharedDb<T::Tree>>
+    where
+        T::Tree: 'static,
+    {
+        let path = path.as_ref().to_path_buf();
+        let (create, sync, lock_policy) = (self.create, self.sync, self.lock_policy);
+        let cache_budget = self.cache_budget;
+        SharedDb::new(move || open_file(&path, create, sync, cache_budget, lock_policy))
+    }
+}
+
+fn open_file<T: DBTree, P: AsRef<Path>>(
+    path: P,
+    create: bool,
+    sync: SyncMode,
+    cache_budget: usize,
+    lock_policy: LockPolicy,
+) -> Result<Db<T>> {
+    let mut storage = FileStorage::open(path, create)?;
+    storage.set_sync(sync);
+    storage.set_cache_budget(cache_budget);
+    LogicalTree::with_storage(storage, lock_policy)
+}
+
+#[cfg(test)]
+mod db_test {
+    use super::{LockPolicy, OpenOptions};
+    use crate::avl_tree::AvlTree;
+    use crate::btree::{BTree, DEFAULT_PAGE_SIZE};
+    use crate::logical_tree::BytesAgent;
+    use crate::serde_interface::{SerdeBincode, SerdeJson};
+    use crate::storage::SyncMode;
+    use tempfile;
+
+    #[test]
+    fn test_open_without_create() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("missing.db");
+        assert!(OpenOptions::new().create(false).open(&path).is_err());
+        assert!(OpenOptions::new().open(&path).is_ok());
+        assert!(OpenOptions::new().create(false).open(&path).is_ok());
+    }
+
+    #[test]
+    fn test_open_bincode_format() {
+        let path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+        let options = OpenOptions::new().format::<SerdeBincode>();
+        let mut db = options.open(&path).unwrap();
+        db.put("hello".to_owned(), "world".to_owned()).unwrap();
+        drop(db);
+        let mut db = options.open(&path).unwrap();
+        assert_eq!(Some("world".to_owned()), db.get("hello").unwrap());
+    }
+
+    #[test]
+    fn test_open_avl_tree() {
+        let path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+        let options = OpenOptions::new()
+            .tree::<AvlTree>()
+            .format::<SerdeBincode>();
+        let mut db = options.open(&path).unwrap();
+        for i in 0..10 {
+            db.put(i.to_string(), i.to_string()).unwrap();
+        }
+        drop(db);
+        let mut db = options.open(&path).unwrap();
+        assert_eq!(Some("7".to_owned()), db.get("7").unwrap());
+    }
+
+    #[test]
+    fn test_open_btree_bytes() {
+        let path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+        let options = OpenOptions::new()
+            .tree::<BTree<SerdeJson, DEFAULT_PAGE_SIZE, BytesAgent>>()
+            .format::<SerdeBincode>();
+        let mut db = options.open(&path).unwrap();
+        db.put(vec![0xde, 0xad], vec![0xbe, 0xef]).unwrap();
+        drop(db);
+        let mut db = options.open(&path).unwrap();
+        assert_eq!(Some(vec![0xbe, 0xef]), db.get([0xde, 0xad]).unwrap());
+        // bincode stores the bytes as they are
+        let content = std::fs::read(&path).unwrap();
+        assert!(content
+            .windows(10)
+            .any(|w| w == [2, 0, 0, 0, 0, 0, 0, 0, 0xbe, 0xef]));
+    }
+
+    #[test]
+    fn test_open_sync_modes() {
+        let path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+        for (i, sync) in [SyncMode::Full, SyncMode::Data, SyncMode::None]
+            .iter()
+            .enumerate()
+        {
+            let mut db = OpenOptions::new().sync(*sync).open(&path).unwrap();
+            db.put(i.to_string(), format!("{:?}", sync)).unwrap();
+        }
+        let mut db = OpenOptions::new().open(&path).unwrap();
+        assert_eq!(Some("Full".to_owned()), db.get("0").unwrap());
+        assert_eq!(Some("Data".to_owned()), db.get("1").unwrap());
+        assert_eq!(Some("None".to_owned()), db.get("2").unwrap());
+    }
+
+    #[test]
+    fn test_open_no_wait() {
+        let path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+        let mut db = OpenOptions::new().open(&path).unwrap();
+        let mut another_db = OpenOptions::new()
+            .lock_policy(LockPolicy::NoWait)
+            .open(&path)
+            .unwrap();
+        db.begin().unwrap();
+        assert!(another_db.begin().is_err());
+        db.commit().unwrap();
+        another_db.begin().unwrap();
+        another_db.commit().unwrap();
+    }
+}
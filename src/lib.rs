@@ -5,7 +5,36 @@
 //!
 //! DBDB aims to preserve data in the face of computer crashes and error conditions. It also avoids holding all data in RAM at once so you can store more data than you have RAM.
 //!
+//! Open a database with [`OpenOptions`](db/struct.OpenOptions.html).
+//!
+
+macro_rules! rc {
+    ($v: expr) => {
+        Rc::new(RefCell::new($v))
+    };
+}
 
+pub mod avl_tree;
+pub mod btree;
+pub mod cache;
+pub mod comparator;
+pub mod db;
 pub mod logical_tree;
+pub mod rb_tree;
 pub mod serde_interface;
+pub mod shared;
 pub mod storage;
+
+pub use avl_tree::AvlTree;
+pub use btree::BTree;
+pub use cache::NodeCache;
+pub use comparator::Comparator;
+pub use db::{Db, LockPolicy, OpenOptions};
+pub use logical_tree::{
+    BinaryTree, BytesAgent, DBTree, LogicalTree, SerdeAgent, StringAgent, WriteBatch,
+};
+pub use rb_tree::RedBlackTree;
+pub use shared::SharedDb;
+pub use storage::{
+    CorruptionError, FormatError, LockedError, MemStorage, SyncMode, FORMAT_VERSION,
+};
@@ -1,43 +1,42 @@
 //! Immutable Tree.
 //!
 
-use std::io::SeekFrom;
 use std::marker::PhantomData;
+use std::ops::{Bound, RangeBounds};
+use std::time::Duration;
 
 use std::cell::RefCell;
 use std::cmp::Ordering;
+use std::collections::HashMap;
 use std::rc::Rc;
 
 use std::clone::Clone;
 use std::convert::From;
 
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use log::debug;
 
-use crate::serde_interface::{SerdeInterface, SerdeJson};
-use crate::storage::{FileStorage, FileStorageGuard, Storage};
-
-macro_rules! rc {
-    ($v: expr) => {
-        Rc::new(RefCell::new($v))
-    };
-}
+use crate::cache::HeapSize;
+use crate::comparator::{Bytewise, Comparator};
+use crate::db::LockPolicy;
+use crate::serde_interface::{compact_bytes, SerdeInterface, SerdeJson};
+use crate::storage::{Commit, FileStorage, Storage};
 
 /// Agent acts like a data bridge between memory and hard disk
 ///
 /// Every agent knows how to dump its inner data to disk and how to load data
 /// from disk
 pub trait Agent {
-    type Inner;
+    type Inner: Clone;
     /// Create a new Agent. There are usually two use cases:
     ///
     /// 1. `Agent::new(Some(T), None)` happens when inserting a new pair of
-    /// <KEY:VALUE>. It creates a data T without addr, waiting to be dumped.
+    ///    <KEY:VALUE>. It creates a data T without addr, waiting to be dumped.
     /// 2. `Agent::new(None, Some(u64))` happends when we load a Agent from
-    /// disk. The value of T won't be loaded to memory until `Agent::get`
-    /// or `Agent::get_mut` is called explicitly.
+    ///    disk. The value of T won't be loaded to memory until `Agent::get`
+    ///    or `Agent::get_mut` is called explicitly.
     fn new(inner: Option<Self::Inner>, addr: Option<u64>) -> Self;
 
     /// Get the addr of the inner data.
@@ -53,21 +52,41 @@ pub trait Agent {
 
     /// Store the inner data to storage
     fn store(&mut self, storage: &mut impl Storage) -> Result<()>;
+
+    /// Drop the inner data if it's stored, keeping only its addr, so that
+    /// it's loaded again when needed. Data not stored yet is kept.
+    fn unload(&mut self) {}
+
+    /// Copy the data at `addr`, and everything it refers to, from storage
+    /// `from` to storage `to`. Return the address in `to`. Nothing is kept
+    /// in memory.
+    fn copy(addr: u64, from: &mut impl Storage, to: &mut impl Storage) -> Result<u64>
+    where
+        Self: Sized;
 }
 
-/// StringAgent works for String
+/// SerdeAgent works for any value serde can handle, e.g. a struct of yours
+///
+/// `T`: the type of value
 ///
 /// `S`: how to serialize / deserialize data
-struct StringAgent<S = SerdeJson> {
-    inner: Option<String>,
+pub struct SerdeAgent<T, S = SerdeJson> {
+    inner: Option<T>,
     pub addr: Option<u64>,
     format: PhantomData<S>,
 }
 
-impl<S: SerdeInterface> Agent for StringAgent<S> {
-    type Inner = String;
-    fn new(inner: Option<String>, addr: Option<u64>) -> Self {
-        StringAgent {
+/// StringAgent works for String
+pub type StringAgent<S = SerdeJson> = SerdeAgent<String, S>;
+
+impl<T, S> Agent for SerdeAgent<T, S>
+where
+    T: Serialize + DeserializeOwned + Clone,
+    S: SerdeInterface,
+{
+    type Inner = T;
+    fn new(inner: Option<T>, addr: Option<u64>) -> Self {
+        SerdeAgent {
             inner,
             addr,
             format: PhantomData,
@@ -78,20 +97,18 @@ impl<S: SerdeInterface> Agent for StringAgent<S> {
         self.addr
     }
 
-    fn get(&mut self, storage: &mut impl Storage) -> Result<Option<&String>> {
-        if self.inner.is_none() && self.addr.is_some() {
-            let _ = storage.seek(SeekFrom::Start(self.addr.unwrap()))?;
+    fn get(&mut self, storage: &mut impl Storage) -> Result<Option<&T>> {
+        if let (None, Some(addr)) = (&self.inner, self.addr) {
             debug!("[Agent] loads a value node");
-            self.inner = Some(S::from_reader(storage)?);
+            self.inner = Some(storage.read_record::<S, _>(addr)?);
         }
         Ok(self.inner.as_ref())
     }
 
-    fn get_mut(&mut self, storage: &mut impl Storage) -> Result<Option<&mut String>> {
-        if self.inner.is_none() && self.addr.is_some() {
-            let _ = storage.seek(SeekFrom::Start(self.addr.unwrap()))?;
+    fn get_mut(&mut self, storage: &mut impl Storage) -> Result<Option<&mut T>> {
+        if let (None, Some(addr)) = (&self.inner, self.addr) {
             debug!("[Agent] loads a value node");
-            self.inner = Some(S::from_reader(storage)?);
+            self.inner = Some(storage.read_record::<S, _>(addr)?);
         }
         Ok(self.inner.as_mut())
     }
@@ -100,13 +117,94 @@ impl<S: SerdeInterface> Agent for StringAgent<S> {
         // Write to disk only when addr is None, which means it is a new item.
         // Remember, we have an immutable storage structure,
         // once an item was stored, we will never write it again.
-        if self.inner.is_some() && self.addr.is_none() {
-            self.addr = Some(storage.get_write_addr()?);
+        if let (Some(inner), None) = (&self.inner, self.addr) {
             debug!("[Agent] writes down a value node");
-            S::to_writer(storage, self.inner.as_ref().unwrap())?;
+            self.addr = Some(storage.write_record::<S, _>(inner)?);
+        }
+        Ok(())
+    }
+
+    fn unload(&mut self) {
+        if self.addr.is_some() {
+            self.inner = None;
+        }
+    }
+
+    fn copy(addr: u64, from: &mut impl Storage, to: &mut impl Storage) -> Result<u64> {
+        let value: T = from.read_record::<S, _>(addr)?;
+        to.write_record::<S, _>(&value)
+    }
+}
+
+// bytes which serialize compactly
+#[derive(Serialize, Deserialize)]
+#[serde(transparent)]
+struct Bytes(#[serde(with = "compact_bytes")] Vec<u8>);
+
+/// BytesAgent works for Vec<u8>, stored as raw bytes in binary formats
+///
+/// `S`: how to serialize / deserialize data
+pub struct BytesAgent<S = SerdeJson> {
+    inner: Option<Vec<u8>>,
+    addr: Option<u64>,
+    format: PhantomData<S>,
+}
+
+impl<S: SerdeInterface> Agent for BytesAgent<S> {
+    type Inner = Vec<u8>;
+    fn new(inner: Option<Vec<u8>>, addr: Option<u64>) -> Self {
+        BytesAgent {
+            inner,
+            addr,
+            format: PhantomData,
+        }
+    }
+
+    fn addr(&self) -> Option<u64> {
+        self.addr
+    }
+
+    fn get(&mut self, storage: &mut impl Storage) -> Result<Option<&Vec<u8>>> {
+        Ok(self.get_mut(storage)?.map(|inner| &*inner))
+    }
+
+    fn get_mut(&mut self, storage: &mut impl Storage) -> Result<Option<&mut Vec<u8>>> {
+        if let (None, Some(addr)) = (&self.inner, self.addr) {
+            debug!("[Agent] loads a bytes value node");
+            let bytes: Bytes = storage.read_record::<S, _>(addr)?;
+            self.inner = Some(bytes.0);
+        }
+        Ok(self.inner.as_mut())
+    }
+
+    fn store(&mut self, storage: &mut impl Storage) -> Result<()> {
+        if let (Some(inner), None) = (&mut self.inner, self.addr) {
+            debug!("[Agent] writes down a bytes value node");
+            // move the bytes into the wrapper for a while, instead of cloning
+            let bytes = Bytes(std::mem::take(inner));
+            let addr = storage.write_record::<S, _>(&bytes);
+            *inner = bytes.0;
+            self.addr = Some(addr?);
         }
         Ok(())
     }
+
+    fn unload(&mut self) {
+        if self.addr.is_some() {
+            self.inner = None;
+        }
+    }
+
+    fn copy(addr: u64, from: &mut impl Storage, to: &mut impl Storage) -> Result<u64> {
+        let bytes: Bytes = from.read_record::<S, _>(addr)?;
+        to.write_record::<S, _>(&bytes)
+    }
+}
+
+// the node agents visited by `Agent::unload`, to bound its cost in tests
+#[cfg(test)]
+thread_local! {
+    pub(crate) static UNLOAD_VISITS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
 }
 
 /// TreeNodeAgent works for TreeNode<V, Self>
@@ -114,10 +212,12 @@ impl<S: SerdeInterface> Agent for StringAgent<S> {
 /// `S`: how to serialize / deserialize data
 ///
 /// `V`: TreeNode's value agent type
-struct TreeNodeAgent<V, S = SerdeJson> {
+pub(crate) struct TreeNodeAgent<V, S = SerdeJson> {
     // this is a recursive struct, be careful
     inner: Option<TreeNode<V, Self>>,
     addr: Option<u64>,
+    // got since the last unload, so stored nodes under it may be loaded
+    visited: bool,
     format: PhantomData<S>,
 }
 
@@ -126,13 +226,23 @@ struct TreeNodeAgent<V, S = SerdeJson> {
 /// When serializing, NodeAgent { inner: TreeNode } -> NodeHD -> File.
 ///
 /// Whene deserializing, File -> NodeHD -> NodeAgent { inner: TreeNode }
-#[derive(Deserialize, Serialize)]
+#[derive(Deserialize, Serialize, Clone)]
 struct TreeNodeHD {
-    key: String,
+    #[serde(with = "compact_bytes")]
+    key: Vec<u8>,
     value_addr: Option<u64>,
     left_addr: Option<u64>,
     right_addr: Option<u64>,
     size: usize,
+    // only balanced trees maintain them
+    height: usize,
+    red: bool,
+}
+
+impl HeapSize for TreeNodeHD {
+    fn heap_size(&self) -> usize {
+        self.key.capacity()
+    }
 }
 
 impl<V, S> TreeNodeAgent<V, S>
@@ -141,9 +251,9 @@ where
     S: SerdeInterface,
 {
     fn load(&mut self, storage: &mut impl Storage) -> Result<()> {
-        if self.inner.is_none() && self.addr.is_some() {
-            let _ = storage.seek(SeekFrom::Start(self.addr.unwrap()))?;
-            let nodehd: TreeNodeHD = S::from_reader(storage)?;
+        self.visited = true;
+        if let (None, Some(addr)) = (&self.inner, self.addr) {
+            let nodehd: TreeNodeHD = storage.read_node::<S, _>(addr)?;
             self.inner = Some(nodehd.into());
             debug!(
                 "[Agent] loads a TreeNode with key {:?} from disk",
@@ -162,6 +272,7 @@ where
     type Inner = TreeNode<V, Self>;
     fn new(inner: Option<Self::Inner>, addr: Option<u64>) -> Self {
         TreeNodeAgent {
+            visited: inner.is_some(),
             inner,
             addr,
             format: PhantomData,
@@ -183,8 +294,7 @@ where
     }
 
     fn store(&mut self, storage: &mut impl Storage) -> Result<()> {
-        if self.inner.is_some() && self.addr.is_none() {
-            let node = self.inner.as_ref().unwrap();
+        if let (Some(node), None) = (&self.inner, self.addr) {
             node.value_agent.borrow_mut().store(storage)?;
             if let Some(ref left) = node.left_agent {
                 left.borrow_mut().store(storage)?;
@@ -192,25 +302,58 @@ where
             if let Some(ref right) = node.right_agent {
                 right.borrow_mut().store(storage)?;
             }
-            self.addr = Some(storage.get_write_addr()?);
             let nodehd: TreeNodeHD = node.into();
             debug!("[Agent] writes down a tree node {:?}", node.key);
-            S::to_writer(storage, &nodehd)?;
+            self.addr = Some(storage.write_record::<S, _>(&nodehd)?);
         }
         Ok(())
     }
+
+    fn unload(&mut self) {
+        #[cfg(test)]
+        UNLOAD_VISITS.with(|visits| visits.set(visits.get() + 1));
+        match (&self.inner, self.addr) {
+            (Some(_), Some(_)) => self.inner = None,
+            // a new node may refer to stored ones, which are only loaded
+            // through it, so a new node not visited has none loaded
+            (Some(node), None) if self.visited => {
+                self.visited = false;
+                node.value_agent.borrow_mut().unload();
+                for child in node.left_agent.iter().chain(node.right_agent.iter()) {
+                    child.borrow_mut().unload();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn copy(addr: u64, from: &mut impl Storage, to: &mut impl Storage) -> Result<u64> {
+        let mut nodehd: TreeNodeHD = from.read_record::<S, _>(addr)?;
+        if let Some(value_addr) = nodehd.value_addr {
+            nodehd.value_addr = Some(V::copy(value_addr, from, to)?);
+        }
+        if let Some(left_addr) = nodehd.left_addr {
+            nodehd.left_addr = Some(Self::copy(left_addr, from, to)?);
+        }
+        if let Some(right_addr) = nodehd.right_addr {
+            nodehd.right_addr = Some(Self::copy(right_addr, from, to)?);
+        }
+        to.write_record::<S, _>(&nodehd)
+    }
 }
 
 /// TreeNode in memory, which we use to search the tree.
 ///
 /// Generic V(Value) means the type of value agent and generic N(Node) means
 /// the type of left and right node agent.
-struct TreeNode<V, N> {
-    key: String,
-    size: usize,
-    value_agent: Rc<RefCell<V>>,
-    left_agent: Option<Rc<RefCell<N>>>,
-    right_agent: Option<Rc<RefCell<N>>>,
+pub(crate) struct TreeNode<V, N> {
+    pub(crate) key: Vec<u8>,
+    pub(crate) size: usize,
+    pub(crate) height: usize,
+    pub(crate) red: bool,
+    pub(crate) value_agent: Rc<RefCell<V>>,
+    pub(crate) left_agent: Option<Rc<RefCell<N>>>,
+    pub(crate) right_agent: Option<Rc<RefCell<N>>>,
 }
 
 impl<V, N> TreeNode<V, N>
@@ -218,13 +361,15 @@ where
     V: Agent,
     N: Agent,
 {
-    fn new(key: String, value: V::Inner) -> Self {
+    pub(crate) fn new(key: Vec<u8>, value: V::Inner) -> Self {
         TreeNode {
             key,
             value_agent: rc!(V::new(Some(value), None)),
             left_agent: None,
             right_agent: None,
             size: 1,
+            height: 1,
+            red: false,
         }
     }
 }
@@ -237,6 +382,8 @@ impl<V, N> Clone for TreeNode<V, N> {
             left_agent: self.left_agent.as_ref().cloned(),
             right_agent: self.right_agent.as_ref().cloned(),
             size: self.size,
+            height: self.height,
+            red: self.red,
         }
     }
 }
@@ -252,12 +399,16 @@ where
         let left_agent = nodehd.left_addr.map(|addr| rc!(N::new(None, Some(addr))));
         let right_agent = nodehd.right_addr.map(|addr| rc!(N::new(None, Some(addr))));
         let size = nodehd.size;
+        let height = nodehd.height;
+        let red = nodehd.red;
         TreeNode {
             key,
             value_agent,
             left_agent,
             right_agent,
             size,
+            height,
+            red,
         }
     }
 }
@@ -274,6 +425,8 @@ where
             left_addr: node.left_agent.as_ref().and_then(|rc| rc.borrow().addr()),
             right_addr: node.right_agent.as_ref().and_then(|rc| rc.borrow().addr()),
             size: node.size,
+            height: node.height,
+            red: node.red,
         }
     }
 }
@@ -284,6 +437,12 @@ pub trait DBTree {
     /// The type of VALUE of KEY:VALUE
     type Value;
 
+    /// The order of KEYs
+    type Comparator: Comparator;
+
+    /// How nodes are serialized, the catalog of buckets uses it too
+    type Format: SerdeInterface;
+
     /// Create a new Tree.
     fn new() -> Result<Self>
     where
@@ -297,36 +456,381 @@ pub trait DBTree {
     fn store(&mut self, storage: &mut impl Storage) -> Result<Option<u64>>;
 
     /// Search the tree for the given KEY
-    fn find(&mut self, key: &str, storage: &mut impl Storage) -> Result<Option<Self::Value>>;
+    fn find(&mut self, key: &[u8], storage: &mut impl Storage) -> Result<Option<Self::Value>>;
 
     /// Insert a new pair of KEY:VALUE
-    fn insert(&mut self, key: String, value: Self::Value, storage: &mut impl Storage)
-        -> Result<()>;
+    fn insert(
+        &mut self,
+        key: Vec<u8>,
+        value: Self::Value,
+        storage: &mut impl Storage,
+    ) -> Result<()>;
 
     /// Delete a TreeNode, if there is any.
-    fn delete(&mut self, key: &str, storage: &mut impl Storage) -> Result<()>;
+    fn delete(&mut self, key: &[u8], storage: &mut impl Storage) -> Result<()>;
+
+    /// Drop the nodes loaded from storage, see `Agent::unload`. Nodes not
+    /// stored yet are kept.
+    fn unload(&mut self);
+
+    /// Replace the tree by a balanced one holding `pairs`, which must be in
+    /// the ascending order of KEY without duplicates. It's built bottom up,
+    /// writing every node once. The tree is unchanged if the order is wrong,
+    /// but the values written before stay in the storage until compaction.
+    ///
+    /// Values are written as they come, but every KEY is kept in memory
+    /// until the tree is built.
+    fn bulk_load<I>(&mut self, pairs: I, storage: &mut impl Storage) -> Result<()>
+    where
+        I: Iterator<Item = (Vec<u8>, Self::Value)>;
+
+    /// The number of KEYs in the tree
+    fn len(&mut self, storage: &mut impl Storage) -> Result<usize>;
+
+    /// The number of KEYs less than `key`, or not greater than it if
+    /// `inclusive`
+    fn rank(&mut self, key: &[u8], inclusive: bool, storage: &mut impl Storage) -> Result<usize>;
+
+    /// The `n`th smallest KEY, counting from 0. Ok(None) will be returned if
+    /// there are not so many KEYs.
+    fn select(&mut self, n: usize, storage: &mut impl Storage) -> Result<Option<Vec<u8>>>;
+
+    /// The number of KEYs within `range`
+    fn count_range(
+        &mut self,
+        range: (Bound<Vec<u8>>, Bound<Vec<u8>>),
+        storage: &mut impl Storage,
+    ) -> Result<usize> {
+        let start = match range.0 {
+            Bound::Included(ref key) => self.rank(key, false, storage)?,
+            Bound::Excluded(ref key) => self.rank(key, true, storage)?,
+            Bound::Unbounded => 0,
+        };
+        let end = match range.1 {
+            Bound::Included(ref key) => self.rank(key, true, storage)?,
+            Bound::Excluded(ref key) => self.rank(key, false, storage)?,
+            Bound::Unbounded => self.len(storage)?,
+        };
+        Ok(end.saturating_sub(start))
+    }
+
+    /// Copy the tree whose root is at `addr` from storage `from` to storage
+    /// `to`, return the address of the new root.
+    fn copy(addr: u64, from: &mut impl Storage, to: &mut impl Storage) -> Result<u64>
+    where
+        Self: Sized;
+
+    /// The cursor type returned by `DBTree::range`
+    type Cursor: Cursor<Value = Self::Value>;
+
+    /// Walk the KEYs within `range` in the order of the comparator. The cursor is pinned
+    /// to the current root, later changes of the tree won't affect it.
+    fn range(&self, range: (Bound<Vec<u8>>, Bound<Vec<u8>>)) -> Self::Cursor;
+}
+
+/// Cursor walks a `DBTree` in the order of KEY, loading nodes from storage
+/// only when they are reached.
+pub trait Cursor {
+    /// The type of VALUE of KEY:VALUE
+    type Value;
+
+    /// Move to the next pair of KEY:VALUE. Ok(None) will be returned if the
+    /// range is exhausted.
+    fn next(&mut self, storage: &mut impl Storage) -> Result<Option<(Vec<u8>, Self::Value)>>;
+}
+
+/// An in-order cursor over a tree made of `TreeNodeAgent`
+///
+/// `stack` holds the nodes whose left subtree has been visited, the top one
+/// is the next node to yield. `C` is the order of the tree.
+pub struct NodeCursor<V, S, C = Bytewise> {
+    stack: Vec<Rc<RefCell<TreeNodeAgent<V, S>>>>,
+    start: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+    // the root we start from, the stack is filled at the first `next`
+    root: Option<Rc<RefCell<TreeNodeAgent<V, S>>>>,
+    order: PhantomData<C>,
+}
+
+impl<V, S, C> NodeCursor<V, S, C>
+where
+    V: Agent,
+    V::Inner: Clone,
+    S: SerdeInterface,
+    C: Comparator,
+{
+    pub(crate) fn new(
+        root: Option<Rc<RefCell<TreeNodeAgent<V, S>>>>,
+        range: (Bound<Vec<u8>>, Bound<Vec<u8>>),
+    ) -> Self {
+        NodeCursor {
+            stack: vec![],
+            start: range.0,
+            end: range.1,
+            root,
+            order: PhantomData,
+        }
+    }
+
+    fn before_start(&self, key: &[u8]) -> bool {
+        match self.start {
+            Bound::Included(ref start) => C::compare(key, start) == Ordering::Less,
+            Bound::Excluded(ref start) => C::compare(key, start) != Ordering::Greater,
+            Bound::Unbounded => false,
+        }
+    }
+
+    fn after_end(&self, key: &[u8]) -> bool {
+        match self.end {
+            Bound::Included(ref end) => C::compare(key, end) == Ordering::Greater,
+            Bound::Excluded(ref end) => C::compare(key, end) != Ordering::Less,
+            Bound::Unbounded => false,
+        }
+    }
+
+    // push the leftmost path of `agent`, skipping nodes before `start`
+    fn descend(
+        &mut self,
+        mut agent: Option<Rc<RefCell<TreeNodeAgent<V, S>>>>,
+        storage: &mut impl Storage,
+    ) -> Result<()> {
+        while let Some(current) = agent {
+            let (skipped, next) = {
+                let mut ag = current.borrow_mut();
+                let node = ag.get(storage)?.unwrap();
+                if self.before_start(&node.key) {
+                    (true, node.right_agent.clone())
+                } else {
+                    (false, node.left_agent.clone())
+                }
+            };
+            if !skipped {
+                self.stack.push(current);
+            }
+            agent = next;
+        }
+        Ok(())
+    }
+}
+
+impl<V, S, C> Cursor for NodeCursor<V, S, C>
+where
+    V: Agent,
+    V::Inner: Clone,
+    S: SerdeInterface,
+    C: Comparator,
+{
+    type Value = V::Inner;
+
+    fn next(&mut self, storage: &mut impl Storage) -> Result<Option<(Vec<u8>, V::Inner)>> {
+        if let Some(root) = self.root.take() {
+            self.descend(Some(root), storage)?;
+        }
+        let agent = match self.stack.pop() {
+            Some(agent) => agent,
+            None => return Ok(None),
+        };
+        let (key, value_agent, right) = {
+            let mut ag = agent.borrow_mut();
+            let node = ag.get(storage)?.unwrap();
+            (
+                node.key.clone(),
+                node.value_agent.clone(),
+                node.right_agent.clone(),
+            )
+        };
+        if self.after_end(&key) {
+            self.stack.clear();
+            return Ok(None);
+        }
+        debug!("[Cursor] reaches node {:?}", key);
+        self.descend(right, storage)?;
+        let value = value_agent.borrow_mut().get(storage)?.cloned();
+        Ok(value.map(|value| (key, value)))
+    }
+}
+
+pub(crate) type NodeAgentCell<V, S> = Rc<RefCell<TreeNodeAgent<V, S>>>;
+
+// the number of keys in the subtree of `agent`
+pub(crate) fn subtree_size<V, S>(
+    agent: &Option<NodeAgentCell<V, S>>,
+    storage: &mut impl Storage,
+) -> Result<usize>
+where
+    V: Agent,
+    S: SerdeInterface,
+{
+    match agent {
+        Some(agent) => Ok(agent.borrow_mut().get(storage)?.unwrap().size),
+        None => Ok(0),
+    }
+}
+
+// the number of keys in the subtree of `agent` less than `key`, or not
+// greater than it if `inclusive`
+pub(crate) fn subtree_rank<V, S, C>(
+    mut agent: Option<NodeAgentCell<V, S>>,
+    key: &[u8],
+    inclusive: bool,
+    storage: &mut impl Storage,
+) -> Result<usize>
+where
+    V: Agent,
+    S: SerdeInterface,
+    C: Comparator,
+{
+    let mut rank = 0;
+    while let Some(current) = agent {
+        let mut current = current.borrow_mut();
+        let node = current.get(storage)?.unwrap();
+        agent = match C::compare(key, &node.key) {
+            Ordering::Less => node.left_agent.clone(),
+            Ordering::Greater => {
+                rank += subtree_size(&node.left_agent, storage)? + 1;
+                node.right_agent.clone()
+            }
+            Ordering::Equal => {
+                let left = subtree_size(&node.left_agent, storage)?;
+                return Ok(rank + left + inclusive as usize);
+            }
+        };
+    }
+    Ok(rank)
+}
+
+// the `n`th smallest key in the subtree of `agent`
+pub(crate) fn subtree_select<V, S>(
+    mut agent: Option<NodeAgentCell<V, S>>,
+    mut n: usize,
+    storage: &mut impl Storage,
+) -> Result<Option<Vec<u8>>>
+where
+    V: Agent,
+    S: SerdeInterface,
+{
+    while let Some(current) = agent {
+        let mut current = current.borrow_mut();
+        let node = current.get(storage)?.unwrap();
+        let left = subtree_size(&node.left_agent, storage)?;
+        agent = match n.cmp(&left) {
+            Ordering::Less => node.left_agent.clone(),
+            Ordering::Equal => return Ok(Some(node.key.clone())),
+            Ordering::Greater => {
+                n -= left + 1;
+                node.right_agent.clone()
+            }
+        };
+    }
+    Ok(None)
+}
+
+// write the values of `pairs`, which must be in ascending order of key, and
+// return the keys with the addrs of their values. The values before a key out
+// of order are written already when it fails.
+pub(crate) fn store_sorted<V, C, I>(
+    pairs: I,
+    storage: &mut impl Storage,
+) -> Result<Vec<(Vec<u8>, u64)>>
+where
+    V: Agent,
+    C: Comparator,
+    I: Iterator<Item = (Vec<u8>, V::Inner)>,
+{
+    let mut entries: Vec<(Vec<u8>, u64)> = vec![];
+    for (key, value) in pairs {
+        if let Some((last, _)) = entries.last() {
+            if C::compare(last, &key) != Ordering::Less {
+                return Err(anyhow!(
+                    "keys must be loaded in ascending order, but {:?} comes after {:?}",
+                    key,
+                    last
+                ));
+            }
+        }
+        let mut agent = V::new(Some(value), None);
+        agent.store(storage)?;
+        entries.push((key, agent.addr().unwrap()));
+    }
+    Ok(entries)
+}
+
+/// A subtree written by a bulk load
+pub(crate) struct BuiltNode {
+    pub(crate) addr: u64,
+    pub(crate) size: usize,
+    pub(crate) height: usize,
+}
+
+// write the node of `entry` above the subtrees `left` and `right`
+pub(crate) fn store_built<S: SerdeInterface>(
+    entry: &(Vec<u8>, u64),
+    left: Option<BuiltNode>,
+    right: Option<BuiltNode>,
+    red: bool,
+    storage: &mut impl Storage,
+) -> Result<BuiltNode> {
+    let size_of = |node: &Option<BuiltNode>| node.as_ref().map_or(0, |node| node.size);
+    let height_of = |node: &Option<BuiltNode>| node.as_ref().map_or(0, |node| node.height);
+    let nodehd = TreeNodeHD {
+        key: entry.0.clone(),
+        value_addr: Some(entry.1),
+        left_addr: left.as_ref().map(|node| node.addr),
+        right_addr: right.as_ref().map(|node| node.addr),
+        size: 1 + size_of(&left) + size_of(&right),
+        height: 1 + height_of(&left).max(height_of(&right)),
+        red,
+    };
+    let addr = storage.write_record::<S, _>(&nodehd)?;
+    Ok(BuiltNode {
+        addr,
+        size: nodehd.size,
+        height: nodehd.height,
+    })
 }
 
-type NodeAgent = TreeNodeAgent<StringAgent>;
-type NodeAgentCell = Rc<RefCell<NodeAgent>>;
-type ValueAgentCell = Rc<RefCell<StringAgent>>;
+// write a perfectly balanced subtree of `entries`, the middle one at the root
+pub(crate) fn store_balanced<S: SerdeInterface>(
+    entries: &[(Vec<u8>, u64)],
+    storage: &mut impl Storage,
+) -> Result<Option<BuiltNode>> {
+    if entries.is_empty() {
+        return Ok(None);
+    }
+    let mid = entries.len() / 2;
+    let left = store_balanced::<S>(&entries[..mid], storage)?;
+    let right = store_balanced::<S>(&entries[mid + 1..], storage)?;
+    store_built::<S>(&entries[mid], left, right, false, storage).map(Some)
+}
 
-struct BinaryTree {
-    root: Option<NodeAgentCell>,
+type ValueAgentCell<V> = Rc<RefCell<V>>;
+// (modified_node, replacement_node)
+type DelMinResult<V, S> = (Option<NodeAgentCell<V, S>>, Option<NodeAgentCell<V, S>>);
+
+/// An unbalanced binary search tree with byte keys
+///
+/// `S`: how to serialize / deserialize tree nodes and values
+///
+/// `V`: the agent of values, like `StringAgent`, `BytesAgent` or `SerdeAgent`
+///
+/// `C`: the order of keys
+pub struct BinaryTree<S = SerdeJson, V = StringAgent<S>, C = Bytewise> {
+    root: Option<NodeAgentCell<V, S>>,
+    order: PhantomData<C>,
 }
 
-impl BinaryTree {
+impl<S: SerdeInterface, V: Agent, C: Comparator> BinaryTree<S, V, C> {
     fn _find(
         &mut self,
-        key: &str,
-        agent: Option<NodeAgentCell>,
+        key: &[u8],
+        agent: Option<NodeAgentCell<V, S>>,
         storage: &mut impl Storage,
-    ) -> Result<Option<ValueAgentCell>> {
+    ) -> Result<Option<ValueAgentCell<V>>> {
         if let Some(agent) = agent {
             let mut agent = agent.borrow_mut();
             let node = agent.get_mut(storage)?.unwrap();
             debug!("[_find] Find alone node {:?}", node.key);
-            match key.cmp(&node.key) {
+            match C::compare(key, &node.key) {
                 Ordering::Less => self._find(key, node.left_agent.clone(), storage),
                 Ordering::Greater => self._find(key, node.right_agent.clone(), storage),
                 Ordering::Equal => Ok(Some(node.value_agent.clone())),
@@ -338,17 +842,17 @@ impl BinaryTree {
 
     fn _insert(
         &mut self,
-        key: String,
-        value: String,
-        agent: Option<NodeAgentCell>,
+        key: Vec<u8>,
+        value: V::Inner,
+        agent: Option<NodeAgentCell<V, S>>,
         storage: &mut impl Storage,
-    ) -> Result<(NodeAgentCell, usize)> {
+    ) -> Result<(NodeAgentCell<V, S>, usize)> {
         if let Some(agent) = agent {
             let mut agent = agent.borrow_mut();
             let node = agent.get(storage)?.unwrap();
             let mut new_node = node.clone();
             let mut size_delta = 0;
-            match key.cmp(&node.key) {
+            match C::compare(&key, &node.key) {
                 Ordering::Less => {
                     let result = self._insert(key, value, node.left_agent.clone(), storage)?;
                     new_node.left_agent = Some(result.0);
@@ -362,22 +866,25 @@ impl BinaryTree {
                     new_node.size += size_delta;
                 }
                 Ordering::Equal => {
-                    new_node.value_agent = rc!(StringAgent::new(Some(value), None));
+                    new_node.value_agent = rc!(V::new(Some(value), None));
                 }
             }
             debug!(
                 "[_insert] Return insert alone node {:?} with size {}",
                 new_node.key, new_node.size
             );
-            Ok((rc!(TreeNodeAgent::new(Some(new_node), None)), size_delta))
+            Ok((
+                rc!(TreeNodeAgent::<V, S>::new(Some(new_node), None)),
+                size_delta,
+            ))
         } else {
             // new a TreeNode
-            debug!(
-                "[_insert] New a TreeNode with {}:{} with size 1",
-                key, value
-            );
+            debug!("[_insert] New a TreeNode with {:?} with size 1", key);
             Ok((
-                rc!(TreeNodeAgent::new(Some(TreeNode::new(key, value)), None)),
+                rc!(TreeNodeAgent::<V, S>::new(
+                    Some(TreeNode::new(key, value)),
+                    None
+                )),
                 1,
             ))
         }
@@ -386,9 +893,9 @@ impl BinaryTree {
     // return (modified_node, replacement_node)
     fn _delmin(
         &mut self,
-        agent: Option<NodeAgentCell>,
+        agent: Option<NodeAgentCell<V, S>>,
         storage: &mut impl Storage,
-    ) -> Result<(Option<NodeAgentCell>, Option<NodeAgentCell>)> {
+    ) -> Result<DelMinResult<V, S>> {
         if let Some(ref ag) = agent {
             let mut ag = ag.borrow_mut();
             let node = ag.get(storage)?.unwrap();
@@ -399,7 +906,7 @@ impl BinaryTree {
             } else {
                 let result = self._delmin(node.left_agent.clone(), storage)?;
                 new_node.left_agent = result.0;
-                let new_agent = Some(rc!(TreeNodeAgent::new(Some(new_node), None)));
+                let new_agent = Some(rc!(TreeNodeAgent::<V, S>::new(Some(new_node), None)));
                 Ok((new_agent, result.1))
             }
         } else {
@@ -409,16 +916,16 @@ impl BinaryTree {
 
     fn _delete(
         &mut self,
-        key: &str,
-        agent: Option<NodeAgentCell>,
+        key: &[u8],
+        agent: Option<NodeAgentCell<V, S>>,
         storage: &mut impl Storage,
-    ) -> Result<Option<NodeAgentCell>> {
+    ) -> Result<Option<NodeAgentCell<V, S>>> {
         if let Some(agent) = agent {
             let mut agent = agent.borrow_mut();
             let node = agent.get(storage)?.unwrap();
             let mut new_node = node.clone();
             new_node.size -= 1;
-            match key.cmp(&node.key) {
+            match C::compare(key, &node.key) {
                 Ordering::Less => {
                     new_node.left_agent = self._delete(key, node.left_agent.clone(), storage)?;
                 }
@@ -447,22 +954,28 @@ impl BinaryTree {
                 "[_delete] Return delete alone node {:?} with size {}",
                 new_node.key, new_node.size
             );
-            Ok(Some(rc!(TreeNodeAgent::new(Some(new_node), None))))
+            Ok(Some(rc!(TreeNodeAgent::<V, S>::new(Some(new_node), None))))
         } else {
             Ok(None)
         }
     }
 }
 
-impl DBTree for BinaryTree {
-    type Value = String;
+impl<S: SerdeInterface, V: Agent, C: Comparator> DBTree for BinaryTree<S, V, C> {
+    type Value = V::Inner;
+    type Comparator = C;
+
+    type Format = S;
 
     fn new() -> Result<Self> {
-        Ok(BinaryTree { root: None })
+        Ok(BinaryTree {
+            root: None,
+            order: PhantomData,
+        })
     }
 
     fn change_view(&mut self, addr: u64) -> Result<()> {
-        self.root = Some(rc!(NodeAgent::new(None, Some(addr))));
+        self.root = Some(rc!(TreeNodeAgent::<V, S>::new(None, Some(addr))));
         Ok(())
     }
 
@@ -476,19 +989,17 @@ impl DBTree for BinaryTree {
         }
     }
 
-    fn find(&mut self, key: &str, storage: &mut impl Storage) -> Result<Option<Self::Value>> {
+    fn find(&mut self, key: &[u8], storage: &mut impl Storage) -> Result<Option<Self::Value>> {
         let agent = self.root.as_ref().cloned();
         if let Some(agent) = self._find(key, agent, storage)? {
-            if let Some(value_ref) = agent.borrow_mut().get(storage)? {
-                return Ok(Some(String::from(value_ref)));
-            }
+            return Ok(agent.borrow_mut().get(storage)?.cloned());
         }
         Ok(None)
     }
 
     fn insert(
         &mut self,
-        key: String,
+        key: Vec<u8>,
         value: Self::Value,
         storage: &mut impl Storage,
     ) -> Result<()> {
@@ -498,7 +1009,7 @@ impl DBTree for BinaryTree {
         Ok(())
     }
 
-    fn delete(&mut self, key: &str, storage: &mut impl Storage) -> Result<()> {
+    fn delete(&mut self, key: &[u8], storage: &mut impl Storage) -> Result<()> {
         let agent = self.root.as_ref().cloned();
         if self._find(key, agent.clone(), storage)?.is_some() {
             debug!("[delete] found key {:?}", key);
@@ -506,51 +1017,207 @@ impl DBTree for BinaryTree {
         }
         Ok(())
     }
+
+    fn unload(&mut self) {
+        if let Some(ref root) = self.root {
+            root.borrow_mut().unload();
+        }
+    }
+
+    fn bulk_load<I>(&mut self, pairs: I, storage: &mut impl Storage) -> Result<()>
+    where
+        I: Iterator<Item = (Vec<u8>, Self::Value)>,
+    {
+        let entries = store_sorted::<V, C, _>(pairs, storage)?;
+        let root = store_balanced::<S>(&entries, storage)?;
+        self.root = root.map(|root| rc!(TreeNodeAgent::<V, S>::new(None, Some(root.addr))));
+        Ok(())
+    }
+
+    fn len(&mut self, storage: &mut impl Storage) -> Result<usize> {
+        subtree_size(&self.root, storage)
+    }
+
+    fn rank(&mut self, key: &[u8], inclusive: bool, storage: &mut impl Storage) -> Result<usize> {
+        subtree_rank::<V, S, C>(self.root.clone(), key, inclusive, storage)
+    }
+
+    fn select(&mut self, n: usize, storage: &mut impl Storage) -> Result<Option<Vec<u8>>> {
+        subtree_select(self.root.clone(), n, storage)
+    }
+
+    fn copy(addr: u64, from: &mut impl Storage, to: &mut impl Storage) -> Result<u64> {
+        TreeNodeAgent::<V, S>::copy(addr, from, to)
+    }
+
+    type Cursor = NodeCursor<V, S, C>;
+
+    fn range(&self, range: (Bound<Vec<u8>>, Bound<Vec<u8>>)) -> Self::Cursor {
+        NodeCursor::new(self.root.as_ref().cloned(), range)
+    }
 }
 
+// The catalog maps the name of a bucket to the root of its tree. The root
+// addr of the storage is the root of the catalog. It's written in the format
+// of the trees.
+type Catalog<S> = BinaryTree<S, SerdeAgent<u64, S>>;
+
+// the name of the default tree in the catalog
+const DEFAULT_BUCKET: &[u8] = b"";
+
 /// High-level user interface storage
 ///
 /// LogicalTree maintains a`Storage`, managing concurrent "transactions".
 ///
 /// LogicalTree maintains a `DBTree`, delegating read/write requests to it.
-
-struct LogicalTree<T> {
-    storage: Rc<RefCell<FileStorage>>,
+/// More trees of the same kind, named buckets, can be kept in the same
+/// storage, see `LogicalTree::bucket`.
+///
+/// Use `OpenOptions` to configure how the underlying file is opened.
+///
+/// `St`: where the data is kept, a file by default
+pub struct LogicalTree<T: DBTree, St: Storage = FileStorage> {
+    storage: Rc<RefCell<St>>,
     // actually, guard is like a token, we hold it during transaction,
     // but don't use it to write
-    guard: Option<FileStorageGuard>,
-    tree: T,
+    guard: Option<St::Guard>,
+    lock_policy: LockPolicy,
+    // the guard is a shared lock, taken by `begin_read`
+    read_only: bool,
+    // cloned by every live `Range` holding a shared lock of its own
+    scans: Rc<()>,
+    catalog: Catalog<T::Format>,
+    // the default tree, and the buckets opened so far
+    trees: HashMap<Vec<u8>, T>,
 }
 
 impl<T: DBTree> LogicalTree<T> {
-    /// Create a new LogicalTree
+    /// Create a new LogicalTree, creating the file if it doesn't exist.
     pub fn new<P: AsRef<std::path::Path>>(path: P) -> Result<Self> {
-        let storage = rc!(FileStorage::new(path)?);
+        Self::with_storage(FileStorage::new(path)?, LockPolicy::Wait)
+    }
+}
+
+impl<T: DBTree, St: Storage> LogicalTree<T, St> {
+    /// Create a LogicalTree over any kind of storage, e.g. a `MemStorage`
+    ///
+    /// It fails if the keys in the storage are ordered by another
+    /// comparator than the one of `T`.
+    pub fn with_storage(mut storage: St, lock_policy: LockPolicy) -> Result<Self> {
+        Self::check_comparator(&mut storage)?;
+        let storage = rc!(storage);
         let guard = None;
-        let tree = T::new()?;
+        let mut trees = HashMap::new();
+        trees.insert(DEFAULT_BUCKET.to_vec(), T::new()?);
         let mut db = LogicalTree {
             storage,
             guard,
-            tree,
+            lock_policy,
+            read_only: false,
+            scans: Rc::new(()),
+            catalog: Catalog::<T::Format>::new()?,
+            trees,
         };
         db.refresh_tree_view()?;
         Ok(db)
     }
 
+    // record the comparator in a new storage, or make sure it's the recorded
+    // one
+    fn check_comparator(storage: &mut St) -> Result<()> {
+        let name = <T::Comparator as Comparator>::name();
+        let recorded = match storage.get_comparator()? {
+            Some(recorded) => recorded,
+            None => {
+                // someone else may record it at the same time
+                let _guard = storage.lock()?;
+                match storage.get_comparator()? {
+                    Some(recorded) => recorded,
+                    None => {
+                        debug!("[check_comparator] record comparator {}", name);
+                        storage.set_comparator(&name)?;
+                        name.clone()
+                    }
+                }
+            }
+        };
+        if recorded != name {
+            return Err(anyhow!(
+                "keys are ordered by comparator {:?}, not {:?}",
+                recorded,
+                name
+            ));
+        }
+        Ok(())
+    }
+
     fn refresh_tree_view(&mut self) -> Result<()> {
         debug!("Try to refresh view");
         let storage = self.storage.clone();
-        if let Some(addr) = storage.borrow_mut().get_root_addr()? {
-            debug!("Get an version of tree view, at addr {}", addr);
-            self.tree.change_view(addr)?;
+        let storage = &mut *storage.borrow_mut();
+        self.catalog = Catalog::<T::Format>::new()?;
+        if let Some(addr) = storage.get_root_addr()? {
+            debug!("Get an version of catalog, at addr {}", addr);
+            self.catalog.change_view(addr)?;
+        }
+        for (name, tree) in self.trees.iter_mut() {
+            match self.catalog.find(name, storage)? {
+                Some(addr) => tree.change_view(addr)?,
+                // the tree may be emptied, or replaced by an empty compacted one
+                None => *tree = T::new()?,
+            }
         }
         Ok(())
     }
 
-    /// Begin a transaction
+    // the tree named `name`, which must be opened by `bucket` first
+    fn tree(&mut self, name: &[u8]) -> &mut T {
+        self.trees.get_mut(name).unwrap()
+    }
+
+    // drop the nodes loaded so far, the node cache of the storage keeps the
+    // hot ones
+    fn unload(&mut self) {
+        self.catalog.unload();
+        for tree in self.trees.values_mut() {
+            tree.unload();
+        }
+    }
+
+    /// Begin a transaction, waiting for the lock according to the lock
+    /// policy
     pub fn begin(&mut self) -> Result<()> {
+        match self.lock_policy {
+            LockPolicy::Wait => self.begin_by(St::lock),
+            LockPolicy::NoWait => self.begin_by(St::try_lock),
+        }
+    }
+
+    /// Begin a transaction, failing at once with a `LockedError` if someone
+    /// else holds the lock, whatever the lock policy is
+    pub fn try_begin(&mut self) -> Result<()> {
+        self.begin_by(St::try_lock)
+    }
+
+    /// Begin a transaction, failing with a `LockedError` if the lock can't
+    /// be acquired within `timeout`
+    pub fn begin_timeout(&mut self, timeout: Duration) -> Result<()> {
+        self.begin_by(|storage| storage.lock_timeout(timeout))
+    }
+
+    fn begin_by<F>(&mut self, lock: F) -> Result<()>
+    where
+        F: FnOnce(&mut St) -> Result<St::Guard>,
+    {
+        self.check_writable()?;
         if self.guard.is_none() {
-            let guard: FileStorageGuard = self.storage.borrow().lock()?;
+            // the lock would wait for them forever
+            if Rc::strong_count(&self.scans) > 1 {
+                return Err(anyhow!(
+                    "can't write while a range of this tree is alive, drop it first"
+                ));
+            }
+            let guard = lock(&mut *self.storage.borrow_mut())?;
             self.guard = Some(guard);
             // now we get an exclusive write access of the underlying file
             // until destroy guard
@@ -559,84 +1226,800 @@ impl<T: DBTree> LogicalTree<T> {
         Ok(())
     }
 
-    /// Commit a transaction
-    pub fn commit(&mut self) -> Result<()> {
-        debug!("[commit] Begin");
-        let storage = self.storage.clone();
-        let storage = &mut *storage.borrow_mut();
-        if let Some(addr) = self.tree.store(storage)? {
-            debug!("commit root addr {}", addr);
-            storage.commit_root_addr(addr)?;
+    /// Begin a read-only transaction. It holds a shared lock, so that other
+    /// readers can go on, while writers and compaction wait for it to end by
+    /// `commit` or `rollback`. Every read sees the same version of the tree.
+    ///
+    /// Inside a transaction, it does nothing.
+    pub fn begin_read(&mut self) -> Result<()> {
+        if self.guard.is_none() {
+            let guard = self.lock_shared()?;
+            self.guard = Some(guard);
+            self.read_only = true;
+            self.refresh_tree_view()?;
         }
-        // end a transacation if there is one
-        let _ = self.guard.take();
         Ok(())
     }
 
-    /// Get value by key from the current db
-    pub fn get(&mut self, key: &str) -> Result<Option<T::Value>> {
-        debug!("[get] Begin with {:?}", key);
-        if self.guard.is_none() {
+    fn lock_shared(&self) -> Result<St::Guard> {
+        match self.lock_policy {
+            LockPolicy::Wait => self.storage.borrow_mut().lock_shared(),
+            LockPolicy::NoWait => self.storage.borrow_mut().try_lock_shared(),
+        }
+    }
+
+    fn check_writable(&self) -> Result<()> {
+        if self.read_only {
+            return Err(anyhow!("can't write inside a read transaction"));
+        }
+        Ok(())
+    }
+
+    // release the lock
+    fn end_transaction(&mut self) {
+        let _ = self.guard.take();
+        self.read_only = false;
+    }
+
+    /// Whether a transaction is going on
+    pub fn in_transaction(&self) -> bool {
+        self.guard.is_some()
+    }
+
+    /// Commit a transaction
+    ///
+    /// Every tree is written down, then the catalog pointing to them, which
+    /// becomes the new root at once.
+    pub fn commit(&mut self) -> Result<()> {
+        debug!("[commit] Begin");
+        if self.read_only {
+            self.end_transaction();
+            return Ok(());
+        }
+        let storage = self.storage.clone();
+        let storage = &mut *storage.borrow_mut();
+        for (name, tree) in self.trees.iter_mut() {
+            let addr = tree.store(storage)?;
+            if addr != self.catalog.find(name, storage)? {
+                match addr {
+                    Some(addr) => self.catalog.insert(name.clone(), addr, storage)?,
+                    None => self.catalog.delete(name, storage)?,
+                }
+            }
+        }
+        let root_addr = self.catalog.store(storage)?;
+        // a transaction changing nothing doesn't make a new version
+        if root_addr != storage.get_root_addr()? {
+            debug!("commit root addr {:?}", root_addr);
+            storage.commit_root_addr(root_addr.unwrap_or(0))?;
+        }
+        // end a transacation if there is one
+        self.end_transaction();
+        self.unload();
+        Ok(())
+    }
+
+    /// Abort a transaction, throwing away every uncommitted change and
+    /// releasing the lock. Nothing is written to the file.
+    pub fn rollback(&mut self) -> Result<()> {
+        debug!("[rollback] Begin");
+        self.end_transaction();
+        // the uncommitted nodes only live in memory, forget them
+        self.forget_trees()?;
+        self.refresh_tree_view()
+    }
+
+    fn forget_trees(&mut self) -> Result<()> {
+        for tree in self.trees.values_mut() {
+            *tree = T::new()?;
+        }
+        Ok(())
+    }
+
+    /// Rewrite the db file, keeping only the nodes and values reachable from
+    /// the current root. Old versions of the tree are dropped.
+    ///
+    /// It holds the lock during the whole work, and can't be called inside a
+    /// transaction.
+    pub fn compact(&mut self) -> Result<()> {
+        debug!("[compact] Begin");
+        if self.guard.is_some() {
+            return Err(anyhow!("can't compact inside a transaction"));
+        }
+        self.begin()?;
+        {
+            let storage = self.storage.clone();
+            let storage = &mut *storage.borrow_mut();
+            let mut compacted = storage.create_compacted()?;
+            let mut catalog = Catalog::<T::Format>::new()?;
+            let mut buckets = self.catalog.range((Bound::Unbounded, Bound::Unbounded));
+            while let Some((name, addr)) = buckets.next(storage)? {
+                let new_addr = T::copy(addr, storage, &mut compacted)?;
+                debug!("[compact] {:?} moves from {} to {}", name, addr, new_addr);
+                catalog.insert(name, new_addr, &mut compacted)?;
+            }
+            if let Some(addr) = catalog.store(&mut compacted)? {
+                compacted.commit_root_addr(addr)?;
+            }
+            storage.replace_with(compacted)?;
+        }
+        // end the transaction, and forget the nodes read from the old file
+        self.end_transaction();
+        self.forget_trees()?;
+        self.refresh_tree_view()
+    }
+
+    /// Open the bucket `name`, another tree kept in the same storage. Its
+    /// changes join the transaction of this `LogicalTree`, and are committed
+    /// together with the changes of the other buckets.
+    ///
+    /// A bucket comes into being with its first key, and is gone once it has
+    /// none. The name can't be empty.
+    ///
+    /// ```no_run
+    /// tree.begin()?;
+    /// tree.bucket("users")?.put("alice", "admin".to_owned())?;
+    /// tree.bucket("sessions")?.put("42", "alice".to_owned())?;
+    /// tree.commit()?;
+    /// ```
+    pub fn bucket<N: Into<Vec<u8>>>(&mut self, name: N) -> Result<Bucket<'_, T, St>> {
+        let name = name.into();
+        if name.as_slice() == DEFAULT_BUCKET {
+            return Err(anyhow!("the name of a bucket can't be empty"));
+        }
+        if !self.trees.contains_key(&name) {
+            let mut tree = T::new()?;
+            let storage = self.storage.clone();
+            let storage = &mut *storage.borrow_mut();
+            if let Some(addr) = self.catalog.find(&name, storage)? {
+                tree.change_view(addr)?;
+            }
+            self.trees.insert(name.clone(), tree);
+        }
+        Ok(Bucket { db: self, name })
+    }
+
+    /// Get value by key from the current db
+    pub fn get<K: AsRef<[u8]>>(&mut self, key: K) -> Result<Option<T::Value>> {
+        self.get_in(DEFAULT_BUCKET, key.as_ref())
+    }
+
+    fn get_in(&mut self, name: &[u8], key: &[u8]) -> Result<Option<T::Value>> {
+        debug!("[get] Begin with {:?}", key);
+        self.read_in(name, |tree, storage| tree.find(key, storage))
+    }
+
+    // read the tree named `name`, which is up to date outside a transaction
+    fn read_in<R, F>(&mut self, name: &[u8], read: F) -> Result<R>
+    where
+        F: FnOnce(&mut T, &mut St) -> Result<R>,
+    {
+        if self.guard.is_none() {
             self.refresh_tree_view()?;
         }
         let storage = self.storage.clone();
         let storage = &mut *storage.borrow_mut();
-        self.tree.find(key, storage)
+        let result = read(self.tree(name), storage);
+        self.unload();
+        result
+    }
+
+    /// The number of keys
+    pub fn len(&mut self) -> Result<usize> {
+        self.read_in(DEFAULT_BUCKET, |tree, storage| tree.len(storage))
+    }
+
+    /// Whether there is no key at all
+    pub fn is_empty(&mut self) -> Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// The number of keys less than `key`, which is the index of `key` in
+    /// the order of keys if it's there
+    pub fn rank<K: AsRef<[u8]>>(&mut self, key: K) -> Result<usize> {
+        let key = key.as_ref();
+        self.read_in(DEFAULT_BUCKET, |tree, storage| {
+            tree.rank(key, false, storage)
+        })
+    }
+
+    /// The `n`th smallest key, counting from 0, e.g. to find where a page
+    /// of `range` starts
+    pub fn select(&mut self, n: usize) -> Result<Option<Vec<u8>>> {
+        self.read_in(DEFAULT_BUCKET, |tree, storage| tree.select(n, storage))
+    }
+
+    /// The number of keys within `range`, without walking through them
+    pub fn count_range<K, R>(&mut self, range: R) -> Result<usize>
+    where
+        K: AsRef<[u8]>,
+        R: RangeBounds<K>,
+    {
+        let range = owned_range(range);
+        self.read_in(DEFAULT_BUCKET, |tree, storage| {
+            tree.count_range(range, storage)
+        })
+    }
+
+    /// Iterate over the pairs whose key is within `range`, in the order of
+    /// the comparator. Outside a transaction, it takes a shared lock like
+    /// `begin_read`, held by the `Range` until it's dropped.
+    ///
+    /// ```no_run
+    /// for pair in tree.range("a".."c") {
+    ///     let (key, value) = pair?;
+    /// }
+    /// ```
+    pub fn range<K, R>(&mut self, range: R) -> Result<Range<T::Cursor, St>>
+    where
+        K: AsRef<[u8]>,
+        R: RangeBounds<K>,
+    {
+        self.range_in(DEFAULT_BUCKET, owned_range(range))
+    }
+
+    fn range_in(
+        &mut self,
+        name: &[u8],
+        range: (Bound<Vec<u8>>, Bound<Vec<u8>>),
+    ) -> Result<Range<T::Cursor, St>> {
+        debug!("[range] Begin");
+        // a transaction keeps the file as it is, or else the range needs
+        // a lock of its own
+        let lock = match self.guard {
+            Some(_) => None,
+            None => {
+                let guard = self.lock_shared()?;
+                self.refresh_tree_view()?;
+                Some((guard, self.scans.clone()))
+            }
+        };
+        let generation = self.storage.borrow().generation();
+        Ok(Range {
+            storage: self.storage.clone(),
+            generation,
+            cursor: self.tree(name).range(range),
+            lock,
+        })
+    }
+
+    /// Iterate over all pairs in the order of key
+    pub fn iter(&mut self) -> Result<Range<T::Cursor, St>> {
+        self.range::<&str, _>(..)
+    }
+
+    /// List the versions kept in the file, the latest first. Compaction
+    /// drops all but the latest one.
+    pub fn versions(&mut self) -> Result<Vec<Commit>> {
+        self.storage.borrow_mut().get_commits()
+    }
+
+    /// Open a read-only view of the tree as it was committed in `version`
+    ///
+    /// The snapshot is gone once the file is compacted, by this handle or
+    /// another one, and reading it fails after.
+    pub fn snapshot_at(&mut self, version: u64) -> Result<Snapshot<T, St>> {
+        debug!("[snapshot_at] Begin with version {}", version);
+        let commit = self
+            .storage
+            .borrow_mut()
+            .get_commit(version)?
+            .ok_or_else(|| anyhow!("version {} is not in the storage", version))?;
+        let generation = self.storage.borrow().generation();
+        let mut snapshot = Snapshot {
+            storage: self.storage.clone(),
+            generation,
+            commit,
+            tree: T::new()?,
+        };
+        snapshot.tree = snapshot.open_tree(DEFAULT_BUCKET)?;
+        Ok(snapshot)
     }
+
     /// Put a pair of key:value into the currnent db
     /// If use this function without a trasaction context, it will be executed
     /// as a single-command transaction. That is:
     /// ```no_run
     /// tree.put("answer".to_owned(), "42".to_owned())?;
     /// ```
-    /// is equivalent to  
+    /// is equivalent to
     /// ```no_run
     /// tree.begin()?;
     /// tree.put("answer".to_owned(), "42".to_owned())?;
     /// tree.commit()?;
     /// ```
-    pub fn put(&mut self, key: String, value: T::Value) -> Result<()> {
+    pub fn put<K: Into<Vec<u8>>>(&mut self, key: K, value: T::Value) -> Result<()> {
+        self.put_in(DEFAULT_BUCKET, key.into(), value)
+    }
+
+    fn put_in(&mut self, name: &[u8], key: Vec<u8>, value: T::Value) -> Result<()> {
         debug!("[put] Begin with {:?}:<Some Value>", key);
         if self.guard.is_none() {
             self.begin()?;
             {
                 let storage = self.storage.clone();
                 let storage = &mut *storage.borrow_mut();
-                self.tree.insert(key, value, storage)?;
+                self.tree(name).insert(key, value, storage)?;
             }
             self.commit()?;
         } else {
+            self.check_writable()?;
             let storage = self.storage.clone();
             let storage = &mut *storage.borrow_mut();
-            self.tree.insert(key, value, storage)?;
+            self.tree(name).insert(key, value, storage)?;
+            self.unload();
         }
         Ok(())
     }
 
-    pub fn del(&mut self, key: &str) -> Result<()> {
+    /// Delete a key from the current db, if there is any. Like `put`, it
+    /// will be executed as a single-command transaction without a
+    /// transaction context.
+    pub fn del<K: AsRef<[u8]>>(&mut self, key: K) -> Result<()> {
+        self.del_in(DEFAULT_BUCKET, key.as_ref())
+    }
+
+    /// Apply the puts and deletes of `batch` in their order, under one lock
+    /// and one commit, so that either all or none of them are seen.
+    ///
+    /// Inside a transaction, they join it instead. If one of them fails
+    /// there, the ones before it are left in the transaction, roll it back to
+    /// drop them.
+    ///
+    /// ```no_run
+    /// let mut batch = WriteBatch::new();
+    /// batch.put("alice", "admin".to_owned()).del("bob");
+    /// tree.write(batch)?;
+    /// ```
+    pub fn write(&mut self, batch: WriteBatch<T::Value>) -> Result<()> {
+        debug!("[write] Begin with {} changes", batch.len());
+        self.change(|tree, storage| {
+            for (key, value) in batch.changes {
+                match value {
+                    Some(value) => tree.insert(key, value, storage)?,
+                    None => tree.delete(&key, storage)?,
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// Replace every pair of the tree by `pairs`, which must come in the
+    /// ascending order of the comparator without duplicated keys. Instead of
+    /// inserting them one by one, a balanced tree is built bottom up, writing
+    /// every node once.
+    ///
+    /// It fails if a key is out of order, leaving the tree unchanged. The
+    /// values written before it are garbage in the file until `compact`.
+    /// The values are written as they come, but every key is kept in memory
+    /// until the tree is built, so mind the size of the keys of a large load.
+    ///
+    /// Like `write`, it commits once, or joins the current transaction.
+    ///
+    /// ```no_run
+    /// let pairs = (0..1000).map(|i| (format!("{:04}", i), i.to_string()));
+    /// tree.bulk_load(pairs)?;
+    /// ```
+    pub fn bulk_load<K, I>(&mut self, pairs: I) -> Result<()>
+    where
+        K: Into<Vec<u8>>,
+        I: IntoIterator<Item = (K, T::Value)>,
+    {
+        debug!("[bulk_load] Begin");
+        let pairs = pairs.into_iter().map(|(key, value)| (key.into(), value));
+        self.change(|tree, storage| tree.bulk_load(pairs, storage))
+    }
+
+    /// Read the value of `key`, None if it's missing, and replace it by the
+    /// result of `f`, deleting the key if it's None. The read and the write
+    /// happen under the same lock, in the current transaction or in one of
+    /// its own, so no other writer comes in between. Return the new value.
+    ///
+    /// ```no_run
+    /// let count = tree.update("visits", |old| {
+    ///     let count: u64 = old.map_or(0, |old| old.parse().unwrap());
+    ///     Some((count + 1).to_string())
+    /// })?;
+    /// ```
+    pub fn update<K, F>(&mut self, key: K, f: F) -> Result<Option<T::Value>>
+    where
+        K: Into<Vec<u8>>,
+        F: FnOnce(Option<T::Value>) -> Option<T::Value>,
+        T::Value: Clone,
+    {
+        let key = key.into();
+        debug!("[update] Begin with {:?}", key);
+        self.change(|tree, storage| {
+            let new = f(tree.find(&key, storage)?);
+            match new {
+                Some(ref value) => tree.insert(key, value.clone(), storage)?,
+                None => tree.delete(&key, storage)?,
+            }
+            Ok(new)
+        })
+    }
+
+    // change the default tree in the current transaction, or in one of its
+    // own, committed if `change` succeeds
+    fn change<R, F>(&mut self, change: F) -> Result<R>
+    where
+        F: FnOnce(&mut T, &mut St) -> Result<R>,
+    {
+        let standalone = self.guard.is_none();
+        if standalone {
+            self.begin()?;
+        } else {
+            self.check_writable()?;
+        }
+        let result = {
+            let storage = self.storage.clone();
+            let storage = &mut *storage.borrow_mut();
+            change(self.tree(DEFAULT_BUCKET), storage)
+        };
+        self.unload();
+        match result {
+            Ok(result) if standalone => {
+                self.commit()?;
+                Ok(result)
+            }
+            Err(e) if standalone => {
+                self.rollback()?;
+                Err(e)
+            }
+            result => result,
+        }
+    }
+
+    fn del_in(&mut self, name: &[u8], key: &[u8]) -> Result<()> {
         debug!("[del] Begin with {:?}", key);
         if self.guard.is_none() {
             self.begin()?;
             {
                 let storage = self.storage.clone();
                 let storage = &mut *storage.borrow_mut();
-                self.tree.delete(key, storage)?;
+                self.tree(name).delete(key, storage)?;
             }
             self.commit()?;
         } else {
+            self.check_writable()?;
             let storage = self.storage.clone();
             let storage = &mut *storage.borrow_mut();
-            self.tree.delete(key, storage)?;
+            self.tree(name).delete(key, storage)?;
+            self.unload();
         }
         Ok(())
     }
 }
 
+impl<T, St> LogicalTree<T, St>
+where
+    T: DBTree,
+    T::Value: PartialEq,
+    St: Storage,
+{
+    /// Set `key` to `new`, or delete it if `new` is None, only if its value
+    /// is `expected`, None meaning the key is missing. The check and the
+    /// write happen under the same lock, in the current transaction or in
+    /// one of its own. Return whether the write happened.
+    ///
+    /// ```no_run
+    /// // renew the lease only if we still hold it
+    /// let renewed = tree.compare_and_swap("leader", Some("me".to_owned()), Some("me".to_owned()))?;
+    /// ```
+    pub fn compare_and_swap<K: Into<Vec<u8>>>(
+        &mut self,
+        key: K,
+        expected: Option<T::Value>,
+        new: Option<T::Value>,
+    ) -> Result<bool> {
+        let key = key.into();
+        debug!("[compare_and_swap] Begin with {:?}", key);
+        self.change(|tree, storage| {
+            if tree.find(&key, storage)? != expected {
+                return Ok(false);
+            }
+            match new {
+                Some(value) => tree.insert(key, value, storage)?,
+                None => tree.delete(&key, storage)?,
+            }
+            Ok(true)
+        })
+    }
+
+    /// Put a pair of key:value only if the key is missing, see
+    /// `compare_and_swap`. Return whether it's put.
+    pub fn put_if_absent<K: Into<Vec<u8>>>(&mut self, key: K, value: T::Value) -> Result<bool> {
+        self.compare_and_swap(key, None, Some(value))
+    }
+
+    /// Delete a key only if its value is `expected`, see `compare_and_swap`.
+    /// Return whether it's deleted.
+    pub fn delete_if_equals<K: Into<Vec<u8>>>(
+        &mut self,
+        key: K,
+        expected: T::Value,
+    ) -> Result<bool> {
+        self.compare_and_swap(key, Some(expected), None)
+    }
+}
+
+/// Puts and deletes collected in memory, to be applied at once by
+/// `LogicalTree::write`
+pub struct WriteBatch<V> {
+    // a value to put, or None to delete the key
+    changes: Vec<(Vec<u8>, Option<V>)>,
+}
+
+impl<V> WriteBatch<V> {
+    /// Create an empty batch
+    pub fn new() -> Self {
+        WriteBatch { changes: vec![] }
+    }
+
+    /// Put a pair of key:value when the batch is written
+    pub fn put<K: Into<Vec<u8>>>(&mut self, key: K, value: V) -> &mut Self {
+        self.changes.push((key.into(), Some(value)));
+        self
+    }
+
+    /// Delete a key when the batch is written
+    pub fn del<K: AsRef<[u8]>>(&mut self, key: K) -> &mut Self {
+        self.changes.push((key.as_ref().to_vec(), None));
+        self
+    }
+
+    /// The number of puts and deletes collected
+    pub fn len(&self) -> usize {
+        self.changes.len()
+    }
+
+    /// Whether nothing is collected
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    /// Forget everything collected
+    pub fn clear(&mut self) {
+        self.changes.clear();
+    }
+}
+
+impl<V> Default for WriteBatch<V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A named tree sharing the storage and the transactions of a
+/// `LogicalTree`, returned by `LogicalTree::bucket`
+pub struct Bucket<'a, T: DBTree, St: Storage = FileStorage> {
+    db: &'a mut LogicalTree<T, St>,
+    name: Vec<u8>,
+}
+
+impl<'a, T: DBTree, St: Storage> Bucket<'a, T, St> {
+    /// The name of the bucket
+    pub fn name(&self) -> &[u8] {
+        &self.name
+    }
+
+    /// Get value by key from the bucket
+    pub fn get<K: AsRef<[u8]>>(&mut self, key: K) -> Result<Option<T::Value>> {
+        self.db.get_in(&self.name, key.as_ref())
+    }
+
+    /// Iterate over the pairs of the bucket whose key is within `range`
+    pub fn range<K, R>(&mut self, range: R) -> Result<Range<T::Cursor, St>>
+    where
+        K: AsRef<[u8]>,
+        R: RangeBounds<K>,
+    {
+        self.db.range_in(&self.name, owned_range(range))
+    }
+
+    /// Iterate over all pairs of the bucket
+    pub fn iter(&mut self) -> Result<Range<T::Cursor, St>> {
+        self.range::<&str, _>(..)
+    }
+
+    /// The number of keys in the bucket
+    pub fn len(&mut self) -> Result<usize> {
+        self.db
+            .read_in(&self.name, |tree, storage| tree.len(storage))
+    }
+
+    /// Whether the bucket has no key at all
+    pub fn is_empty(&mut self) -> Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// The number of keys in the bucket less than `key`
+    pub fn rank<K: AsRef<[u8]>>(&mut self, key: K) -> Result<usize> {
+        let key = key.as_ref();
+        self.db
+            .read_in(&self.name, |tree, storage| tree.rank(key, false, storage))
+    }
+
+    /// The `n`th smallest key in the bucket, counting from 0
+    pub fn select(&mut self, n: usize) -> Result<Option<Vec<u8>>> {
+        self.db
+            .read_in(&self.name, |tree, storage| tree.select(n, storage))
+    }
+
+    /// The number of keys in the bucket within `range`
+    pub fn count_range<K, R>(&mut self, range: R) -> Result<usize>
+    where
+        K: AsRef<[u8]>,
+        R: RangeBounds<K>,
+    {
+        let range = owned_range(range);
+        self.db
+            .read_in(&self.name, |tree, storage| tree.count_range(range, storage))
+    }
+
+    /// Put a pair of key:value into the bucket, like `LogicalTree::put`
+    pub fn put<K: Into<Vec<u8>>>(&mut self, key: K, value: T::Value) -> Result<()> {
+        self.db.put_in(&self.name, key.into(), value)
+    }
+
+    /// Delete a key from the bucket, like `LogicalTree::del`
+    pub fn del<K: AsRef<[u8]>>(&mut self, key: K) -> Result<()> {
+        self.db.del_in(&self.name, key.as_ref())
+    }
+}
+
+// clone the bounds of `range` into byte strings
+fn owned_range<K, R>(range: R) -> (Bound<Vec<u8>>, Bound<Vec<u8>>)
+where
+    K: AsRef<[u8]>,
+    R: RangeBounds<K>,
+{
+    let to_owned = |bound: Bound<&K>| match bound {
+        Bound::Included(k) => Bound::Included(k.as_ref().to_vec()),
+        Bound::Excluded(k) => Bound::Excluded(k.as_ref().to_vec()),
+        Bound::Unbounded => Bound::Unbounded,
+    };
+    (to_owned(range.start_bound()), to_owned(range.end_bound()))
+}
+
+/// A read-only view of a `LogicalTree` at an older version, returned by
+/// `LogicalTree::snapshot_at`
+pub struct Snapshot<T, St = FileStorage> {
+    storage: Rc<RefCell<St>>,
+    // the generation of the storage the addresses belong to
+    generation: u64,
+    commit: Commit,
+    tree: T,
+}
+
+impl<T: DBTree, St: Storage> Snapshot<T, St> {
+    /// The version of the snapshot
+    pub fn version(&self) -> u64 {
+        self.commit.version
+    }
+
+    /// When the version was committed, in seconds since the unix epoch
+    pub fn timestamp(&self) -> u64 {
+        self.commit.timestamp
+    }
+
+    /// View the bucket `name` as it was in the version of the snapshot,
+    /// empty if it had no keys then
+    pub fn bucket<N: AsRef<[u8]>>(&self, name: N) -> Result<Snapshot<T, St>> {
+        let name = name.as_ref();
+        if name == DEFAULT_BUCKET {
+            return Err(anyhow!("the name of a bucket can't be empty"));
+        }
+        Ok(Snapshot {
+            storage: self.storage.clone(),
+            generation: self.generation,
+            commit: self.commit,
+            tree: self.open_tree(name)?,
+        })
+    }
+
+    // the tree of the bucket `name` in the version of the snapshot
+    fn open_tree(&self, name: &[u8]) -> Result<T> {
+        let mut tree = T::new()?;
+        if let Some(addr) = self.commit.root_addr {
+            let storage = self.storage.clone();
+            let storage = &mut *storage.borrow_mut();
+            check_generation(storage, self.generation)?;
+            let mut catalog = Catalog::<T::Format>::new()?;
+            catalog.change_view(addr)?;
+            if let Some(addr) = catalog.find(name, storage)? {
+                tree.change_view(addr)?;
+            }
+        }
+        Ok(tree)
+    }
+
+    /// Get value by key from the snapshot
+    pub fn get<K: AsRef<[u8]>>(&mut self, key: K) -> Result<Option<T::Value>> {
+        let storage = self.storage.clone();
+        let storage = &mut *storage.borrow_mut();
+        check_generation(storage, self.generation)?;
+        let value = self.tree.find(key.as_ref(), storage);
+        self.tree.unload();
+        value
+    }
+
+    /// Iterate over the pairs of the snapshot whose key is within `range`
+    pub fn range<K, R>(&self, range: R) -> Range<T::Cursor, St>
+    where
+        K: AsRef<[u8]>,
+        R: RangeBounds<K>,
+    {
+        Range {
+            storage: self.storage.clone(),
+            generation: self.generation,
+            cursor: self.tree.range(owned_range(range)),
+            lock: None,
+        }
+    }
+
+    /// Iterate over all pairs of the snapshot
+    pub fn iter(&self) -> Range<T::Cursor, St> {
+        self.range::<&str, _>(..)
+    }
+}
+
+/// An iterator over a range of a `LogicalTree`, returned by
+/// `LogicalTree::range`
+///
+/// It sees the tree as it was when the iterator was created. Created
+/// outside a transaction, it holds a shared lock until it's dropped, so
+/// writers and compaction wait for it, and so does its own `LogicalTree`:
+/// writing there fails meanwhile. Inside a transaction, it relies on the
+/// lock of the transaction, and fails once the file is compacted under it.
+pub struct Range<C, St: Storage = FileStorage> {
+    storage: Rc<RefCell<St>>,
+    generation: u64,
+    cursor: C,
+    // the shared lock taken outside a transaction, and the token telling
+    // the tree it's held
+    lock: Option<(St::Guard, Rc<()>)>,
+}
+
+impl<C: Cursor, St: Storage> Iterator for Range<C, St> {
+    type Item = Result<(Vec<u8>, C::Value)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let storage = self.storage.clone();
+        let storage = &mut *storage.borrow_mut();
+        if let Err(e) = check_generation(storage, self.generation) {
+            return Some(Err(e));
+        }
+        self.cursor.next(storage).transpose()
+    }
+}
+
+// addresses of an older generation lead to garbage in a compacted file
+fn check_generation(storage: &impl Storage, generation: u64) -> Result<()> {
+    if storage.generation() != generation {
+        return Err(anyhow!("the storage was compacted, the view is gone"));
+    }
+    Ok(())
+}
+
+/// Dropping a `LogicalTree` in the middle of a transaction rolls it back:
+/// uncommitted changes are never written and the lock is released.
+impl<T: DBTree, St: Storage> Drop for LogicalTree<T, St> {
+    fn drop(&mut self) {
+        if self.guard.take().is_some() {
+            debug!("[drop] Discard an uncommitted transaction");
+        }
+    }
+}
+
 #[cfg(test)]
 mod tree_test {
     use super::*;
+    use crate::db::OpenOptions;
+    use crate::storage::MemStorage;
     use pretty_env_logger;
     use std::path::PathBuf;
+    use std::sync::mpsc;
     use std::thread;
     use std::time;
     use tempfile;
@@ -680,9 +2063,10 @@ mod tree_test {
         match handle.join() {
             Ok(d) => assert!(
                 d >= one_sec,
-                format!("another process did't block for enough time, only {:?}", d)
+                "another process did't block for enough time, only {:?}",
+                d
             ),
-            Err(e) => assert!(false, format!("subthread panic: {:?}", e)),
+            Err(e) => panic!("subthread panic: {:?}", e),
         }
 
         tree.put("c".to_owned(), "3".to_owned()).unwrap();
@@ -720,6 +2104,179 @@ mod tree_test {
         // no commit here
     }
 
+    #[test]
+    fn test_binary_tree_rollback() {
+        let path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+        let mut tree = LogicalTree::<BinaryTree>::new(&path).unwrap();
+        tree.put("a".to_owned(), "1".to_owned()).unwrap();
+        let write_addr = tree.storage.borrow_mut().get_write_addr().unwrap();
+
+        tree.begin().unwrap();
+        tree.put("b".to_owned(), "2".to_owned()).unwrap();
+        tree.del("a").unwrap();
+        assert_eq!(Some("2".to_owned()), tree.get("b").unwrap());
+        tree.rollback().unwrap();
+        assert_eq!(Some("1".to_owned()), tree.get("a").unwrap());
+        assert_eq!(None, tree.get("b").unwrap());
+        // nothing reached the file
+        let end_addr = tree.storage.borrow_mut().get_write_addr().unwrap();
+        assert_eq!(write_addr, end_addr);
+
+        // the lock is released, and a rollback of an empty db is empty too
+        let empty_path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+        let mut empty = LogicalTree::<BinaryTree>::new(&empty_path).unwrap();
+        empty.begin().unwrap();
+        empty.put("a".to_owned(), "1".to_owned()).unwrap();
+        empty.rollback().unwrap();
+        assert_eq!(None, empty.get("a").unwrap());
+        empty.put("b".to_owned(), "2".to_owned()).unwrap();
+        assert_eq!(Some("2".to_owned()), empty.get("b").unwrap());
+    }
+
+    #[test]
+    fn test_binary_tree_drop_in_transaction() {
+        let path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+        let mut tree = LogicalTree::<BinaryTree>::new(&path).unwrap();
+        tree.begin().unwrap();
+        tree.put("a".to_owned(), "1".to_owned()).unwrap();
+        drop(tree);
+
+        let mut tree = LogicalTree::<BinaryTree>::new(&path).unwrap();
+        // would block forever if the lock was still held
+        tree.begin().unwrap();
+        assert_eq!(None, tree.get("a").unwrap());
+        tree.commit().unwrap();
+    }
+
+    #[test]
+    fn test_binary_tree_range() {
+        let path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+        let mut tree = LogicalTree::<BinaryTree>::new(&path).unwrap();
+        tree.begin().unwrap();
+        for key in &["d", "b", "f", "a", "c", "e", "g"] {
+            tree.put(key.to_string(), key.to_uppercase()).unwrap();
+        }
+        tree.commit().unwrap();
+
+        let keys = |range: Range<NodeCursor<StringAgent, SerdeJson>>| -> Vec<String> {
+            range
+                .map(|pair| String::from_utf8(pair.unwrap().0).unwrap())
+                .collect()
+        };
+        assert_eq!(
+            vec!["a", "b", "c", "d", "e", "f", "g"],
+            keys(tree.iter().unwrap())
+        );
+        assert_eq!(vec!["b", "c", "d"], keys(tree.range("b".."e").unwrap()));
+        assert_eq!(
+            vec!["b", "c", "d", "e"],
+            keys(tree.range("b"..="e").unwrap())
+        );
+        assert_eq!(vec!["e", "f", "g"], keys(tree.range("dd"..).unwrap()));
+        assert_eq!(vec!["a"], keys(tree.range(.."b").unwrap()));
+        assert!(keys(tree.range("x"..).unwrap()).is_empty());
+
+        let mut range = tree.range("c"..="c").unwrap();
+        assert_eq!(
+            (b"c".to_vec(), "C".to_owned()),
+            range.next().unwrap().unwrap()
+        );
+        assert!(range.next().is_none());
+    }
+
+    #[test]
+    fn test_binary_tree_range_is_pinned() {
+        use crate::storage::LockedError;
+        let path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+        let mut tree = LogicalTree::<BinaryTree>::new(&path).unwrap();
+        let mut another_tree = OpenOptions::new()
+            .lock_policy(LockPolicy::NoWait)
+            .open(&path)
+            .unwrap();
+        tree.put("a".to_owned(), "1".to_owned()).unwrap();
+        tree.put("b".to_owned(), "2".to_owned()).unwrap();
+
+        // writers wait for a range outside a transaction
+        let range = tree.iter().unwrap();
+        let err = another_tree
+            .put("aa".to_owned(), "3".to_owned())
+            .unwrap_err();
+        assert!(err.downcast_ref::<LockedError>().is_some());
+        assert!(another_tree.compact().is_err());
+        // its own tree can't write meanwhile, but still reads
+        assert!(tree.put("c".to_owned(), "4".to_owned()).is_err());
+        assert_eq!(2, tree.iter().unwrap().count());
+        drop(range);
+        another_tree.put("aa".to_owned(), "3".to_owned()).unwrap();
+        another_tree.del("b").unwrap();
+
+        // inside a transaction, later changes aren't seen
+        tree.begin().unwrap();
+        let range = tree.iter().unwrap();
+        tree.put("c".to_owned(), "4".to_owned()).unwrap();
+        tree.commit().unwrap();
+        let pairs: Vec<(Vec<u8>, String)> = range.map(|pair| pair.unwrap()).collect();
+        assert_eq!(
+            vec![
+                (b"a".to_vec(), "1".to_owned()),
+                (b"aa".to_vec(), "3".to_owned())
+            ],
+            pairs
+        );
+
+        // compaction by another thread waits for the range
+        let range = tree.iter().unwrap();
+        let (done, compacted) = mpsc::channel();
+        let compactor = {
+            let path = path.to_path_buf();
+            thread::spawn(move || {
+                let mut tree = LogicalTree::<BinaryTree>::new(&path).unwrap();
+                tree.compact().unwrap();
+                done.send(()).unwrap();
+            })
+        };
+        thread::sleep(time::Duration::from_millis(50));
+        assert!(compacted.try_recv().is_err());
+        let keys: Vec<_> = range.map(|pair| pair.unwrap().0).collect();
+        assert_eq!(vec![b"a".to_vec(), b"aa".to_vec(), b"c".to_vec()], keys);
+        compactor.join().unwrap();
+        assert_eq!(Some("4".to_owned()), tree.get("c").unwrap());
+    }
+
+    #[test]
+    fn test_binary_tree_compact() {
+        let path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+        let mut tree = LogicalTree::<BinaryTree>::new(&path).unwrap();
+        let mut another_tree = LogicalTree::<BinaryTree>::new(&path).unwrap();
+        for i in 0..50 {
+            tree.put((i % 10).to_string(), i.to_string()).unwrap();
+        }
+        tree.del("5").unwrap();
+        let before = std::fs::metadata(&path).unwrap().len();
+        tree.compact().unwrap();
+        let after = std::fs::metadata(&path).unwrap().len();
+        assert!(
+            after * 5 < before,
+            "{} is not much less than {}",
+            after,
+            before
+        );
+
+        assert_eq!(Some("49".to_owned()), tree.get("9").unwrap());
+        assert_eq!(None, tree.get("5").unwrap());
+        assert_eq!(9, tree.iter().unwrap().count());
+
+        // the other handle follows the compacted file, both for read and write
+        assert_eq!(Some("40".to_owned()), another_tree.get("0").unwrap());
+        another_tree.put("a".to_owned(), "A".to_owned()).unwrap();
+        assert_eq!(Some("A".to_owned()), tree.get("a").unwrap());
+        drop(tree);
+        drop(another_tree);
+        let mut tree = LogicalTree::<BinaryTree>::new(&path).unwrap();
+        assert_eq!(Some("A".to_owned()), tree.get("a").unwrap());
+        assert_eq!(Some("41".to_owned()), tree.get("1").unwrap());
+    }
+
     #[test]
     fn test_binary_tree_store() {
         let path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
@@ -735,4 +2292,570 @@ mod tree_test {
         assert_eq!(Some("shadow".to_owned()), tree.get("arc").unwrap());
         assert_eq!(None, tree.get("zoo").unwrap());
     }
+
+    #[test]
+    fn test_binary_tree_mem_storage() {
+        let storage = MemStorage::new();
+        let mut tree =
+            LogicalTree::<BinaryTree, _>::with_storage(storage.clone(), LockPolicy::Wait).unwrap();
+        let mut another_tree =
+            LogicalTree::<BinaryTree, _>::with_storage(storage.clone(), LockPolicy::NoWait)
+                .unwrap();
+        for key in &["c", "a", "b"] {
+            tree.put(key.to_string(), key.to_uppercase()).unwrap();
+        }
+        tree.begin().unwrap();
+        tree.del("a").unwrap();
+        assert!(another_tree.begin().is_err());
+        assert_eq!(Some("A".to_owned()), another_tree.get("a").unwrap());
+        tree.commit().unwrap();
+        assert_eq!(None, another_tree.get("a").unwrap());
+
+        // compaction waits for the range
+        let pinned = another_tree.iter().unwrap();
+        let (done, compacted) = mpsc::channel();
+        let compactor = thread::spawn(move || {
+            let mut tree =
+                LogicalTree::<BinaryTree, _>::with_storage(storage, LockPolicy::Wait).unwrap();
+            tree.compact().unwrap();
+            tree.put("d".to_owned(), "D".to_owned()).unwrap();
+            done.send(()).unwrap();
+        });
+        thread::sleep(time::Duration::from_millis(50));
+        assert!(compacted.try_recv().is_err());
+        let pairs: Vec<_> = pinned
+            .map(|pair| String::from_utf8(pair.unwrap().0).unwrap())
+            .collect();
+        assert_eq!(vec!["b", "c"], pairs);
+        compactor.join().unwrap();
+        let pairs: Vec<_> = another_tree
+            .iter()
+            .unwrap()
+            .map(|pair| String::from_utf8(pair.unwrap().0).unwrap())
+            .collect();
+        assert_eq!(vec!["b", "c", "d"], pairs);
+    }
+
+    #[test]
+    fn test_binary_tree_snapshot() {
+        let path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+        let mut tree = LogicalTree::<BinaryTree>::new(&path).unwrap();
+        tree.put("a".to_owned(), "1".to_owned()).unwrap();
+        tree.put("a".to_owned(), "2".to_owned()).unwrap();
+        tree.put("b".to_owned(), "3".to_owned()).unwrap();
+        tree.begin().unwrap();
+        tree.del("a").unwrap();
+        tree.del("b").unwrap();
+        tree.commit().unwrap();
+        // nothing changes, no new version
+        tree.begin().unwrap();
+        tree.get("a").unwrap();
+        tree.commit().unwrap();
+
+        let versions: Vec<_> = tree.versions().unwrap().iter().map(|c| c.version).collect();
+        assert_eq!(vec![4, 3, 2, 1], versions);
+        assert_eq!(
+            Some("1".to_owned()),
+            tree.snapshot_at(1).unwrap().get("a").unwrap()
+        );
+        let snapshot = tree.snapshot_at(3).unwrap();
+        let pairs: Vec<_> = snapshot.iter().map(|pair| pair.unwrap()).collect();
+        assert_eq!(
+            vec![
+                (b"a".to_vec(), "2".to_owned()),
+                (b"b".to_vec(), "3".to_owned())
+            ],
+            pairs
+        );
+        assert_eq!(None, tree.snapshot_at(4).unwrap().get("a").unwrap());
+        assert!(tree.snapshot_at(5).is_err());
+
+        // the emptied tree is committed too
+        let mut reopened = LogicalTree::<BinaryTree>::new(&path).unwrap();
+        assert_eq!(None, reopened.get("b").unwrap());
+
+        // versions go on after compaction, but the older ones are dropped
+        let mut old = tree.snapshot_at(4).unwrap();
+        let mut old_pairs = tree.snapshot_at(3).unwrap().iter();
+        tree.compact().unwrap();
+        tree.put("c".to_owned(), "4".to_owned()).unwrap();
+        let versions: Vec<_> = tree.versions().unwrap().iter().map(|c| c.version).collect();
+        assert_eq!(vec![5], versions);
+        // and so are the views of them
+        assert!(old.get("a").is_err());
+        assert!(old_pairs.next().unwrap().is_err());
+
+        // also when another handle compacts the file
+        let mut old = tree.snapshot_at(5).unwrap();
+        reopened.compact().unwrap();
+        assert_eq!(Some("4".to_owned()), tree.get("c").unwrap());
+        assert!(old.get("c").is_err());
+    }
+
+    #[test]
+    fn test_binary_tree_bytes() {
+        let path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+        let mut tree = LogicalTree::<BinaryTree<SerdeJson, BytesAgent>>::new(&path).unwrap();
+        tree.put(vec![0xff, 0x00], vec![1, 2, 3]).unwrap();
+        tree.put(vec![0x7f], vec![0xc3, 0x28]).unwrap();
+        tree.put("text", b"plain".to_vec()).unwrap();
+        tree.del([0x7f]).unwrap();
+
+        let mut tree = LogicalTree::<BinaryTree<SerdeJson, BytesAgent>>::new(&path).unwrap();
+        assert_eq!(Some(vec![1, 2, 3]), tree.get([0xff, 0x00]).unwrap());
+        assert_eq!(None, tree.get([0x7f]).unwrap());
+        // byte-wise order
+        let keys: Vec<_> = tree.iter().unwrap().map(|pair| pair.unwrap().0).collect();
+        assert_eq!(vec![b"text".to_vec(), vec![0xff, 0x00]], keys);
+
+        // no json arrays of numbers in the file
+        let content = std::fs::read(&path).unwrap();
+        let content = String::from_utf8_lossy(&content);
+        assert!(content.contains(r#"{"hex":"ff00"}"#));
+        assert!(content.contains(r#""plain""#));
+        assert!(!content.contains("[1,2,3]"));
+    }
+
+    #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+    struct Point {
+        x: i32,
+        y: i32,
+    }
+
+    #[test]
+    fn test_binary_tree_serde_agent() {
+        let path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+        let mut tree = LogicalTree::<BinaryTree<SerdeJson, SerdeAgent<Point>>>::new(&path).unwrap();
+        tree.put("a", Point { x: 1, y: 2 }).unwrap();
+        tree.put("b", Point { x: 3, y: 4 }).unwrap();
+
+        let mut tree = LogicalTree::<BinaryTree<SerdeJson, SerdeAgent<Point>>>::new(&path).unwrap();
+        assert_eq!(Some(Point { x: 1, y: 2 }), tree.get("a").unwrap());
+        let values: Vec<_> = tree.iter().unwrap().map(|pair| pair.unwrap().1).collect();
+        assert_eq!(vec![Point { x: 1, y: 2 }, Point { x: 3, y: 4 }], values);
+        // encoded once, not as a json string inside json
+        let content = std::fs::read(&path).unwrap();
+        let content = String::from_utf8_lossy(&content);
+        assert!(content.contains(r#"{"x":3,"y":4}"#));
+    }
+
+    #[test]
+    fn test_binary_tree_comparator() {
+        use crate::comparator::CaseInsensitive;
+        type Tree = BinaryTree<SerdeJson, StringAgent, CaseInsensitive>;
+        let path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+        let mut tree = LogicalTree::<Tree>::new(&path).unwrap();
+        tree.put("b", "1".to_owned()).unwrap();
+        tree.put("A", "2".to_owned()).unwrap();
+        tree.put("B", "3".to_owned()).unwrap();
+        tree.put("c", "4".to_owned()).unwrap();
+        assert_eq!(Some("3".to_owned()), tree.get("b").unwrap());
+        let keys: Vec<_> = tree.iter().unwrap().map(|pair| pair.unwrap().0).collect();
+        assert_eq!(vec![b"A".to_vec(), b"b".to_vec(), b"c".to_vec()], keys);
+        tree.del("C").unwrap();
+        assert_eq!(None, tree.get("c").unwrap());
+
+        // the comparator is kept by compaction, and others are rejected
+        tree.compact().unwrap();
+        drop(tree);
+        let error = LogicalTree::<BinaryTree>::new(&path).err().unwrap();
+        assert!(error.to_string().contains("case-insensitive"));
+        let mut tree = LogicalTree::<Tree>::new(&path).unwrap();
+        assert_eq!(Some("2".to_owned()), tree.get("a").unwrap());
+    }
+
+    #[test]
+    fn test_binary_tree_buckets() {
+        let path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+        let mut tree = LogicalTree::<BinaryTree>::new(&path).unwrap();
+        let mut another_tree = LogicalTree::<BinaryTree>::new(&path).unwrap();
+        tree.put("a", "default".to_owned()).unwrap();
+        tree.begin().unwrap();
+        tree.bucket("users")
+            .unwrap()
+            .put("a", "alice".to_owned())
+            .unwrap();
+        tree.bucket("sessions")
+            .unwrap()
+            .put("a", "42".to_owned())
+            .unwrap();
+        assert_eq!(
+            None,
+            another_tree.bucket("users").unwrap().get("a").unwrap()
+        );
+        tree.commit().unwrap();
+
+        // both buckets are committed in a single version
+        assert_eq!(2, tree.versions().unwrap().len());
+        assert_eq!(
+            Some("alice".to_owned()),
+            another_tree.bucket("users").unwrap().get("a").unwrap()
+        );
+        assert_eq!(Some("default".to_owned()), another_tree.get("a").unwrap());
+        assert_eq!(
+            None,
+            another_tree.bucket("nothing").unwrap().get("a").unwrap()
+        );
+        assert!(tree.bucket("").is_err());
+
+        tree.begin().unwrap();
+        tree.bucket("users")
+            .unwrap()
+            .put("b", "bob".to_owned())
+            .unwrap();
+        tree.bucket("sessions").unwrap().del("a").unwrap();
+        tree.rollback().unwrap();
+        assert_eq!(1, tree.bucket("users").unwrap().iter().unwrap().count());
+        tree.bucket("sessions").unwrap().del("a").unwrap();
+
+        // the buckets of older versions
+        let snapshot = tree.snapshot_at(2).unwrap();
+        assert_eq!(
+            Some("42".to_owned()),
+            snapshot.bucket("sessions").unwrap().get("a").unwrap()
+        );
+        let snapshot = tree.snapshot_at(1).unwrap();
+        assert_eq!(None, snapshot.bucket("users").unwrap().get("a").unwrap());
+        assert!(snapshot.bucket("").is_err());
+        // the catalog is json, like the trees
+        let content = std::fs::read(&path).unwrap();
+        assert!(String::from_utf8_lossy(&content).contains(r#""sessions""#));
+
+        tree.compact().unwrap();
+        drop(tree);
+        let mut tree = LogicalTree::<BinaryTree>::new(&path).unwrap();
+        let mut users = tree.bucket("users").unwrap();
+        let pairs: Vec<_> = users.iter().unwrap().map(|pair| pair.unwrap()).collect();
+        assert_eq!(vec![(b"a".to_vec(), "alice".to_owned())], pairs);
+        assert_eq!(0, tree.bucket("sessions").unwrap().iter().unwrap().count());
+        assert_eq!(Some("default".to_owned()), tree.get("a").unwrap());
+    }
+
+    fn check_order_statistics<T: DBTree<Value = String>>() {
+        let path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+        let mut tree = LogicalTree::<T>::new(&path).unwrap();
+        tree.begin().unwrap();
+        for i in 0..200 {
+            tree.put(format!("{:03}", (i * 7) % 200), i.to_string())
+                .unwrap();
+        }
+        for i in (0..200).filter(|i| i % 3 == 0) {
+            tree.del(format!("{:03}", i)).unwrap();
+        }
+        tree.commit().unwrap();
+
+        let keys: Vec<Vec<u8>> = tree.iter().unwrap().map(|pair| pair.unwrap().0).collect();
+        assert_eq!(keys.len(), tree.len().unwrap());
+        for (n, key) in keys.iter().enumerate() {
+            assert_eq!(Some(key.clone()), tree.select(n).unwrap());
+            assert_eq!(n, tree.rank(key).unwrap());
+        }
+        assert_eq!(None, tree.select(keys.len()).unwrap());
+        assert_eq!(0, tree.rank("").unwrap());
+        assert_eq!(keys.len(), tree.rank("999").unwrap());
+        // multiples of 3 are gone: 33 keys below 050, 8 within 100..=110
+        assert_eq!(0, tree.rank("000").unwrap());
+        assert_eq!(33, tree.rank("050").unwrap());
+        assert_eq!(8, tree.count_range("100"..="110").unwrap());
+        assert_eq!(8, tree.count_range("099".."111").unwrap());
+        assert_eq!(keys.len(), tree.count_range::<&str, _>(..).unwrap());
+        assert_eq!(0, tree.count_range("5".."4").unwrap());
+    }
+
+    #[test]
+    fn test_order_statistics() {
+        use crate::avl_tree::AvlTree;
+        use crate::btree::BTree;
+        use crate::rb_tree::RedBlackTree;
+        check_order_statistics::<BinaryTree>();
+        check_order_statistics::<AvlTree>();
+        check_order_statistics::<RedBlackTree>();
+        check_order_statistics::<BTree<SerdeJson, 256>>();
+
+        let mut tree =
+            LogicalTree::<BinaryTree, _>::with_storage(MemStorage::new(), LockPolicy::Wait)
+                .unwrap();
+        assert!(tree.is_empty().unwrap());
+        tree.bucket("b").unwrap().put("x", "1".to_owned()).unwrap();
+        assert_eq!(1, tree.bucket("b").unwrap().len().unwrap());
+        assert!(tree.is_empty().unwrap());
+    }
+
+    #[test]
+    fn test_binary_tree_read_transaction() {
+        let path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+        let mut tree = LogicalTree::<BinaryTree>::new(&path).unwrap();
+        tree.put("a", "1".to_owned()).unwrap();
+        let mut writer = OpenOptions::new()
+            .lock_policy(LockPolicy::NoWait)
+            .open(&path)
+            .unwrap();
+        let mut reader = LogicalTree::<BinaryTree>::new(&path).unwrap();
+
+        tree.begin_read().unwrap();
+        reader.begin_read().unwrap();
+        assert!(writer.begin().is_err());
+        assert!(writer.compact().is_err());
+        assert_eq!(Some("1".to_owned()), tree.get("a").unwrap());
+        assert!(tree.put("b", "2".to_owned()).is_err());
+        assert!(tree.begin().is_err());
+        tree.commit().unwrap();
+        assert!(writer.put("b", "2".to_owned()).is_err());
+        reader.rollback().unwrap();
+
+        writer.put("b", "2".to_owned()).unwrap();
+        assert_eq!(2, tree.iter().unwrap().count());
+        assert_eq!(1, tree.versions().unwrap()[0].version - 1);
+    }
+
+    #[test]
+    fn test_binary_tree_try_begin() {
+        use crate::storage::LockedError;
+        let path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+        let mut tree = LogicalTree::<BinaryTree>::new(&path).unwrap();
+        let mut another_tree = LogicalTree::<BinaryTree>::new(&path).unwrap();
+        tree.begin().unwrap();
+        let error = another_tree.try_begin().unwrap_err();
+        assert!(error.downcast_ref::<LockedError>().is_some());
+        let start = time::Instant::now();
+        let timeout = time::Duration::from_millis(200);
+        let error = another_tree.begin_timeout(timeout).unwrap_err();
+        assert!(error.downcast_ref::<LockedError>().is_some());
+        assert!(start.elapsed() >= timeout);
+        assert!(!another_tree.in_transaction());
+        tree.rollback().unwrap();
+
+        // the lock is released in time
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let thread_path = path.to_path_buf();
+        let handle = thread::spawn(move || {
+            let mut tree = LogicalTree::<BinaryTree>::new(&thread_path).unwrap();
+            tree.begin().unwrap();
+            sender.send(()).unwrap();
+            thread::sleep(time::Duration::from_millis(200));
+            tree.put("a", "1".to_owned()).unwrap();
+            tree.commit().unwrap();
+        });
+        receiver.recv().unwrap();
+        another_tree
+            .begin_timeout(time::Duration::from_secs(10))
+            .unwrap();
+        assert_eq!(Some("1".to_owned()), another_tree.get("a").unwrap());
+        another_tree.commit().unwrap();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_binary_tree_node_cache() {
+        let mut storage = MemStorage::new();
+        storage.set_cache_budget(1000);
+        let mut tree =
+            LogicalTree::<BinaryTree, _>::with_storage(storage, LockPolicy::Wait).unwrap();
+        tree.begin().unwrap();
+        for i in 0..100 {
+            tree.put(format!("{:03}", i), i.to_string()).unwrap();
+        }
+        tree.commit().unwrap();
+
+        // a long read transaction keeps no more nodes than the budget
+        tree.begin_read().unwrap();
+        for i in 0..100 {
+            assert_eq!(Some(i.to_string()), tree.get(format!("{:03}", i)).unwrap());
+        }
+        let root = tree.tree(DEFAULT_BUCKET).root.clone().unwrap();
+        assert!(root.borrow().inner.is_none());
+        {
+            let mut storage = tree.storage.borrow_mut();
+            let cache = storage.node_cache().unwrap();
+            assert!(cache.used() <= 1000);
+            // decoded nodes take more than their records
+            assert!(cache.used() >= cache.len() * std::mem::size_of::<TreeNodeHD>());
+        }
+        tree.commit().unwrap();
+
+        // the nodes are found in the cache by later views
+        tree.get("000").unwrap();
+        let misses = tree.storage.borrow_mut().node_cache().unwrap().misses();
+        tree.get("000").unwrap();
+        assert_eq!(
+            misses,
+            tree.storage.borrow_mut().node_cache().unwrap().misses()
+        );
+    }
+
+    // the node agents the last of `n` puts in a transaction makes
+    // `Agent::unload` visit, after as many committed
+    fn unload_visits<T: DBTree<Value = String>>(n: usize) -> usize {
+        let mut tree =
+            LogicalTree::<T, _>::with_storage(MemStorage::new(), LockPolicy::Wait).unwrap();
+        // scatter the keys, so the BinaryTree stays balanced enough
+        let key = |i: usize| format!("{:05}", i * 7919 % 10007);
+        for i in 0..n {
+            tree.put(key(i), i.to_string()).unwrap();
+        }
+        tree.begin().unwrap();
+        for i in n..2 * n {
+            tree.put(key(i), i.to_string()).unwrap();
+        }
+        // reads load stored nodes
+        for i in 0..n {
+            tree.get(key(i)).unwrap();
+        }
+        UNLOAD_VISITS.with(|visits| visits.set(0));
+        tree.put(key(2 * n), String::new()).unwrap();
+        tree.commit().unwrap();
+        UNLOAD_VISITS.with(|visits| visits.get())
+    }
+
+    #[test]
+    fn test_unload_visits_only_the_path() {
+        use crate::avl_tree::AvlTree;
+        use crate::btree::BTree;
+        use crate::rb_tree::RedBlackTree;
+
+        // every put of a transaction only unloads around its path, instead
+        // of every node not committed yet
+        for n in [500, 2000] {
+            assert!(unload_visits::<BinaryTree>(n) < 200);
+            assert!(unload_visits::<AvlTree>(n) < 200);
+            assert!(unload_visits::<RedBlackTree>(n) < 200);
+            assert!(unload_visits::<BTree>(n) < 200);
+        }
+    }
+
+    #[test]
+    fn test_binary_tree_write_batch() {
+        let mut tree =
+            LogicalTree::<BinaryTree, _>::with_storage(MemStorage::new(), LockPolicy::Wait)
+                .unwrap();
+        tree.put("c", "3".to_owned()).unwrap();
+        let mut batch = WriteBatch::new();
+        batch
+            .put("a", "1".to_owned())
+            .put("b", "2".to_owned())
+            .del("c")
+            .put("a", "10".to_owned());
+        assert_eq!(4, batch.len());
+        tree.write(batch).unwrap();
+        // one commit for the whole batch
+        assert_eq!(2, tree.versions().unwrap().len());
+        let pairs: Vec<_> = tree.iter().unwrap().map(|pair| pair.unwrap()).collect();
+        assert_eq!(
+            vec![
+                (b"a".to_vec(), "10".to_owned()),
+                (b"b".to_vec(), "2".to_owned())
+            ],
+            pairs
+        );
+
+        // inside a transaction, the batch is rolled back with it
+        tree.begin().unwrap();
+        let mut batch = WriteBatch::new();
+        batch.put("d", "4".to_owned()).del("a");
+        tree.write(batch).unwrap();
+        assert_eq!(Some("4".to_owned()), tree.get("d").unwrap());
+        tree.rollback().unwrap();
+        assert_eq!(None, tree.get("d").unwrap());
+        assert_eq!(Some("10".to_owned()), tree.get("a").unwrap());
+        assert_eq!(2, tree.versions().unwrap().len());
+    }
+
+    #[test]
+    fn test_binary_tree_bulk_load() {
+        let mut tree =
+            LogicalTree::<BinaryTree, _>::with_storage(MemStorage::new(), LockPolicy::Wait)
+                .unwrap();
+        tree.put("old", "gone".to_owned()).unwrap();
+        let pairs = (0..1000).map(|i| (format!("{:04}", i), i.to_string()));
+        tree.bulk_load(pairs).unwrap();
+        // a single commit replacing every pair
+        assert_eq!(2, tree.versions().unwrap().len());
+        assert_eq!(None, tree.get("old").unwrap());
+        assert_eq!(1000, tree.len().unwrap());
+        assert_eq!(Some("567".to_owned()), tree.get("0567").unwrap());
+        // sorted keys give a balanced tree, not a list
+        let root = tree.tree(DEFAULT_BUCKET).root.clone().unwrap();
+        let height = {
+            let storage = &mut *tree.storage.borrow_mut();
+            root.borrow_mut().get(storage).unwrap().unwrap().height
+        };
+        assert_eq!(10, height);
+
+        // keys out of order change nothing
+        let pairs = vec![("b", "2".to_owned()), ("a", "1".to_owned())];
+        assert!(tree.bulk_load(pairs).is_err());
+        assert!(!tree.in_transaction());
+        assert_eq!(2, tree.versions().unwrap().len());
+        assert_eq!(1000, tree.len().unwrap());
+    }
+
+    #[test]
+    fn test_binary_tree_compare_and_swap() {
+        let mut tree =
+            LogicalTree::<BinaryTree, _>::with_storage(MemStorage::new(), LockPolicy::Wait)
+                .unwrap();
+        assert!(tree.put_if_absent("lease", "a".to_owned()).unwrap());
+        assert!(!tree.put_if_absent("lease", "b".to_owned()).unwrap());
+        assert_eq!(Some("a".to_owned()), tree.get("lease").unwrap());
+
+        assert!(!tree
+            .compare_and_swap("lease", Some("b".to_owned()), Some("c".to_owned()))
+            .unwrap());
+        assert!(tree
+            .compare_and_swap("lease", Some("a".to_owned()), Some("c".to_owned()))
+            .unwrap());
+        assert_eq!(Some("c".to_owned()), tree.get("lease").unwrap());
+
+        assert!(!tree.delete_if_equals("lease", "a".to_owned()).unwrap());
+        assert!(tree.delete_if_equals("lease", "c".to_owned()).unwrap());
+        assert_eq!(None, tree.get("lease").unwrap());
+        // failed checks don't make versions
+        assert_eq!(3, tree.versions().unwrap().len());
+
+        // inside a transaction, the check sees its own writes
+        tree.begin().unwrap();
+        tree.put("job", "queued".to_owned()).unwrap();
+        assert!(tree
+            .compare_and_swap("job", Some("queued".to_owned()), Some("taken".to_owned()))
+            .unwrap());
+        assert!(tree.in_transaction());
+        tree.rollback().unwrap();
+        assert_eq!(None, tree.get("job").unwrap());
+
+        tree.begin_read().unwrap();
+        assert!(tree.put_if_absent("job", "queued".to_owned()).is_err());
+        tree.commit().unwrap();
+    }
+
+    #[test]
+    fn test_binary_tree_update() {
+        let mut tree =
+            LogicalTree::<BinaryTree, _>::with_storage(MemStorage::new(), LockPolicy::Wait)
+                .unwrap();
+        let increment = |old: Option<String>| {
+            let count: u32 = old.map_or(0, |old| old.parse().unwrap());
+            Some((count + 1).to_string())
+        };
+        assert_eq!(
+            Some("1".to_owned()),
+            tree.update("count", increment).unwrap()
+        );
+        assert_eq!(
+            Some("2".to_owned()),
+            tree.update("count", increment).unwrap()
+        );
+        assert_eq!(Some("2".to_owned()), tree.get("count").unwrap());
+        assert_eq!(2, tree.versions().unwrap().len());
+
+        // None deletes the key
+        assert_eq!(None, tree.update("count", |_| None).unwrap());
+        assert_eq!(None, tree.get("count").unwrap());
+        // and deleting a missing key changes nothing
+        tree.update("missing", |old| old).unwrap();
+        assert_eq!(3, tree.versions().unwrap().len());
+
+        tree.begin_read().unwrap();
+        assert!(tree.update("count", increment).is_err());
+        tree.commit().unwrap();
+    }
 }
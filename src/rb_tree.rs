@@ -0,0 +1,561 @@
+//! Left-leaning red-black tree.
+//!
+//! A port of Sedgewick's LLRB to the immutable storage. A node is never
+//! changed in place: rotations and color flips copy the nodes they touch,
+//! and only the copies reachable from the new root are written by `store`.
+//! The color of each node is persisted in `TreeNodeHD`.
+
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::marker::PhantomData;
+use std::ops::Bound;
+use std::rc::Rc;
+
+use anyhow::Result;
+use log::debug;
+
+use crate::comparator::{Bytewise, Comparator};
+use crate::logical_tree::{
+    store_built, store_sorted, subtree_rank, subtree_select, subtree_size, Agent, BuiltNode,
+    DBTree, NodeAgentCell, NodeCursor, StringAgent, TreeNode, TreeNodeAgent,
+};
+use crate::serde_interface::{SerdeInterface, SerdeJson};
+use crate::storage::Storage;
+
+type Node<V, S> = TreeNode<V, TreeNodeAgent<V, S>>;
+
+/// A left-leaning red-black tree with byte keys. Red links always lean left
+/// and every path from the root to a leaf passes the same number of black
+/// links, so the depth is at most 2 * log2(n).
+///
+/// `S`: how to serialize / deserialize tree nodes and values
+///
+/// `V`: the agent of values, like `StringAgent`, `BytesAgent` or `SerdeAgent`
+///
+/// `C`: the order of keys
+pub struct RedBlackTree<S = SerdeJson, V = StringAgent<S>, C = Bytewise> {
+    root: Option<NodeAgentCell<V, S>>,
+    order: PhantomData<C>,
+}
+
+impl<S: SerdeInterface, V: Agent, C: Comparator> RedBlackTree<S, V, C> {
+    fn _node(agent: &NodeAgentCell<V, S>, storage: &mut impl Storage) -> Result<Node<V, S>> {
+        let mut agent = agent.borrow_mut();
+        Ok(agent.get(storage)?.unwrap().clone())
+    }
+
+    fn _is_red(agent: &Option<NodeAgentCell<V, S>>, storage: &mut impl Storage) -> Result<bool> {
+        match agent {
+            Some(agent) => Ok(agent.borrow_mut().get(storage)?.unwrap().red),
+            None => Ok(false),
+        }
+    }
+
+    // is the left child of `agent` red
+    fn _is_left_red(
+        agent: &Option<NodeAgentCell<V, S>>,
+        storage: &mut impl Storage,
+    ) -> Result<bool> {
+        match agent {
+            Some(agent) => {
+                let left = agent.borrow_mut().get(storage)?.unwrap().left_agent.clone();
+                Self::_is_red(&left, storage)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn _size(agent: &Option<NodeAgentCell<V, S>>, storage: &mut impl Storage) -> Result<usize> {
+        match agent {
+            Some(agent) => Ok(agent.borrow_mut().get(storage)?.unwrap().size),
+            None => Ok(0),
+        }
+    }
+
+    // fix size of a copied node and wrap it in a new agent
+    fn _make(mut node: Node<V, S>, storage: &mut impl Storage) -> Result<NodeAgentCell<V, S>> {
+        node.size =
+            1 + Self::_size(&node.left_agent, storage)? + Self::_size(&node.right_agent, storage)?;
+        Ok(rc!(TreeNodeAgent::<V, S>::new(Some(node), None)))
+    }
+
+    fn _rotate_left(mut node: Node<V, S>, storage: &mut impl Storage) -> Result<Node<V, S>> {
+        let mut right = Self::_node(node.right_agent.as_ref().unwrap(), storage)?;
+        debug!("[_rotate_left] {:?} goes down to the left", node.key);
+        node.right_agent = right.left_agent.take();
+        right.red = node.red;
+        node.red = true;
+        right.left_agent = Some(Self::_make(node, storage)?);
+        Ok(right)
+    }
+
+    fn _rotate_right(mut node: Node<V, S>, storage: &mut impl Storage) -> Result<Node<V, S>> {
+        let mut left = Self::_node(node.left_agent.as_ref().unwrap(), storage)?;
+        debug!("[_rotate_right] {:?} goes down to the right", node.key);
+        node.left_agent = left.right_agent.take();
+        left.red = node.red;
+        node.red = true;
+        left.right_agent = Some(Self::_make(node, storage)?);
+        Ok(left)
+    }
+
+    // flip the colors of a node and its two children
+    fn _flip_colors(node: &mut Node<V, S>, storage: &mut impl Storage) -> Result<()> {
+        node.red = !node.red;
+        for child in [&mut node.left_agent, &mut node.right_agent].iter_mut() {
+            if let Some(agent) = child.take() {
+                let mut child_node = Self::_node(&agent, storage)?;
+                child_node.red = !child_node.red;
+                **child = Some(Self::_make(child_node, storage)?);
+            }
+        }
+        Ok(())
+    }
+
+    // restore the LLRB properties on the way up
+    fn _balance(mut node: Node<V, S>, storage: &mut impl Storage) -> Result<Node<V, S>> {
+        if Self::_is_red(&node.right_agent, storage)? && !Self::_is_red(&node.left_agent, storage)?
+        {
+            node = Self::_rotate_left(node, storage)?;
+        }
+        if Self::_is_red(&node.left_agent, storage)?
+            && Self::_is_left_red(&node.left_agent, storage)?
+        {
+            node = Self::_rotate_right(node, storage)?;
+        }
+        if Self::_is_red(&node.left_agent, storage)? && Self::_is_red(&node.right_agent, storage)? {
+            Self::_flip_colors(&mut node, storage)?;
+        }
+        Ok(node)
+    }
+
+    // make node.left or one of its children red
+    fn _move_red_left(mut node: Node<V, S>, storage: &mut impl Storage) -> Result<Node<V, S>> {
+        Self::_flip_colors(&mut node, storage)?;
+        if Self::_is_left_red(&node.right_agent, storage)? {
+            let right = Self::_node(node.right_agent.as_ref().unwrap(), storage)?;
+            let right = Self::_rotate_right(right, storage)?;
+            node.right_agent = Some(Self::_make(right, storage)?);
+            node = Self::_rotate_left(node, storage)?;
+            Self::_flip_colors(&mut node, storage)?;
+        }
+        Ok(node)
+    }
+
+    // make node.right or one of its children red
+    fn _move_red_right(mut node: Node<V, S>, storage: &mut impl Storage) -> Result<Node<V, S>> {
+        Self::_flip_colors(&mut node, storage)?;
+        if Self::_is_left_red(&node.left_agent, storage)? {
+            node = Self::_rotate_right(node, storage)?;
+            Self::_flip_colors(&mut node, storage)?;
+        }
+        Ok(node)
+    }
+
+    // write a perfectly balanced 2-3 tree of `entries` with `height` levels,
+    // as a LLRB: a 2-3 node of two keys is a black node with a red left
+    // child. Every path passes `height` black nodes.
+    fn _bulk_load(
+        entries: &[(Vec<u8>, u64)],
+        height: u32,
+        storage: &mut impl Storage,
+    ) -> Result<Option<BuiltNode>> {
+        if height == 0 {
+            return Ok(None);
+        }
+        // a child of `height - 1` levels holds at most `most` keys
+        let most = 3usize.pow(height - 1) - 1;
+        let n = entries.len();
+        if n - 1 <= 2 * most {
+            let at = (n - 1) / 2;
+            let left = Self::_bulk_load(&entries[..at], height - 1, storage)?;
+            let right = Self::_bulk_load(&entries[at + 1..], height - 1, storage)?;
+            store_built::<S>(&entries[at], left, right, false, storage).map(Some)
+        } else {
+            let rest = n - 2;
+            let (a, b) = (rest / 3, rest / 3 + 1 + (rest + 1) / 3);
+            let left = Self::_bulk_load(&entries[..a], height - 1, storage)?;
+            let middle = Self::_bulk_load(&entries[a + 1..b], height - 1, storage)?;
+            let red = store_built::<S>(&entries[a], left, middle, true, storage)?;
+            let right = Self::_bulk_load(&entries[b + 1..], height - 1, storage)?;
+            store_built::<S>(&entries[b], Some(red), right, false, storage).map(Some)
+        }
+    }
+
+    fn _find(
+        &self,
+        key: &[u8],
+        mut agent: Option<NodeAgentCell<V, S>>,
+        storage: &mut impl Storage,
+    ) -> Result<Option<Rc<RefCell<V>>>> {
+        while let Some(current) = agent {
+            let mut current = current.borrow_mut();
+            let node = current.get(storage)?.unwrap();
+            agent = match C::compare(key, &node.key) {
+                Ordering::Less => node.left_agent.clone(),
+                Ordering::Greater => node.right_agent.clone(),
+                Ordering::Equal => return Ok(Some(node.value_agent.clone())),
+            };
+        }
+        Ok(None)
+    }
+
+    fn _insert(
+        &mut self,
+        key: Vec<u8>,
+        value: V::Inner,
+        agent: Option<NodeAgentCell<V, S>>,
+        storage: &mut impl Storage,
+    ) -> Result<Node<V, S>> {
+        if let Some(agent) = agent {
+            let mut node = Self::_node(&agent, storage)?;
+            match C::compare(&key, &node.key) {
+                Ordering::Less => {
+                    let left = node.left_agent.take();
+                    let left = self._insert(key, value, left, storage)?;
+                    node.left_agent = Some(Self::_make(left, storage)?);
+                }
+                Ordering::Greater => {
+                    let right = node.right_agent.take();
+                    let right = self._insert(key, value, right, storage)?;
+                    node.right_agent = Some(Self::_make(right, storage)?);
+                }
+                Ordering::Equal => {
+                    node.value_agent = rc!(V::new(Some(value), None));
+                    return Ok(node);
+                }
+            }
+            Self::_balance(node, storage)
+        } else {
+            debug!("[_insert] New a red TreeNode with key {:?}", key);
+            let mut node = TreeNode::new(key, value);
+            node.red = true;
+            Ok(node)
+        }
+    }
+
+    fn _min(agent: &NodeAgentCell<V, S>, storage: &mut impl Storage) -> Result<Node<V, S>> {
+        let mut node = Self::_node(agent, storage)?;
+        while let Some(left) = node.left_agent.take() {
+            node = Self::_node(&left, storage)?;
+        }
+        Ok(node)
+    }
+
+    fn _delmin(
+        &mut self,
+        agent: &NodeAgentCell<V, S>,
+        storage: &mut impl Storage,
+    ) -> Result<Option<NodeAgentCell<V, S>>> {
+        let mut node = Self::_node(agent, storage)?;
+        if node.left_agent.is_none() {
+            return Ok(None);
+        }
+        if !Self::_is_red(&node.left_agent, storage)?
+            && !Self::_is_left_red(&node.left_agent, storage)?
+        {
+            node = Self::_move_red_left(node, storage)?;
+        }
+        let left = node.left_agent.take().unwrap();
+        node.left_agent = self._delmin(&left, storage)?;
+        let node = Self::_balance(node, storage)?;
+        Ok(Some(Self::_make(node, storage)?))
+    }
+
+    // the key must be in the subtree of `agent`
+    fn _delete(
+        &mut self,
+        key: &[u8],
+        agent: &NodeAgentCell<V, S>,
+        storage: &mut impl Storage,
+    ) -> Result<Option<NodeAgentCell<V, S>>> {
+        let mut node = Self::_node(agent, storage)?;
+        if C::compare(key, &node.key) == Ordering::Less {
+            if !Self::_is_red(&node.left_agent, storage)?
+                && !Self::_is_left_red(&node.left_agent, storage)?
+            {
+                node = Self::_move_red_left(node, storage)?;
+            }
+            let left = node.left_agent.take().unwrap();
+            node.left_agent = self._delete(key, &left, storage)?;
+        } else {
+            if Self::_is_red(&node.left_agent, storage)? {
+                node = Self::_rotate_right(node, storage)?;
+            }
+            if C::compare(key, &node.key) == Ordering::Equal && node.right_agent.is_none() {
+                return Ok(None);
+            }
+            if !Self::_is_red(&node.right_agent, storage)?
+                && !Self::_is_left_red(&node.right_agent, storage)?
+            {
+                node = Self::_move_red_right(node, storage)?;
+            }
+            let right = node.right_agent.take().unwrap();
+            if C::compare(key, &node.key) == Ordering::Equal {
+                let min_node = Self::_min(&right, storage)?;
+                node.key = min_node.key;
+                node.value_agent = min_node.value_agent;
+                node.right_agent = self._delmin(&right, storage)?;
+            } else {
+                node.right_agent = self._delete(key, &right, storage)?;
+            }
+        }
+        let node = Self::_balance(node, storage)?;
+        Ok(Some(Self::_make(node, storage)?))
+    }
+}
+
+impl<S: SerdeInterface, V: Agent, C: Comparator> DBTree for RedBlackTree<S, V, C> {
+    type Value = V::Inner;
+    type Comparator = C;
+
+    type Format = S;
+
+    fn new() -> Result<Self> {
+        Ok(RedBlackTree {
+            root: None,
+            order: PhantomData,
+        })
+    }
+
+    fn change_view(&mut self, addr: u64) -> Result<()> {
+        self.root = Some(rc!(TreeNodeAgent::<V, S>::new(None, Some(addr))));
+        Ok(())
+    }
+
+    fn store(&mut self, storage: &mut impl Storage) -> Result<Option<u64>> {
+        if let Some(ref root) = self.root {
+            root.borrow_mut().store(storage)?;
+            let addr = root.borrow().addr().unwrap();
+            Ok(Some(addr))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn find(&mut self, key: &[u8], storage: &mut impl Storage) -> Result<Option<Self::Value>> {
+        let agent = self.root.as_ref().cloned();
+        if let Some(agent) = self._find(key, agent, storage)? {
+            return Ok(agent.borrow_mut().get(storage)?.cloned());
+        }
+        Ok(None)
+    }
+
+    fn insert(
+        &mut self,
+        key: Vec<u8>,
+        value: Self::Value,
+        storage: &mut impl Storage,
+    ) -> Result<()> {
+        let agent = self.root.as_ref().cloned();
+        let mut root = self._insert(key, value, agent, storage)?;
+        root.red = false;
+        self.root = Some(Self::_make(root, storage)?);
+        Ok(())
+    }
+
+    fn delete(&mut self, key: &[u8], storage: &mut impl Storage) -> Result<()> {
+        let agent = match self.root.as_ref().cloned() {
+            Some(agent) => agent,
+            None => return Ok(()),
+        };
+        if self._find(key, Some(agent.clone()), storage)?.is_none() {
+            return Ok(());
+        }
+        debug!("[delete] found key {:?}", key);
+        let mut root = Self::_node(&agent, storage)?;
+        let agent = if !Self::_is_red(&root.left_agent, storage)?
+            && !Self::_is_red(&root.right_agent, storage)?
+        {
+            root.red = true;
+            Self::_make(root, storage)?
+        } else {
+            agent
+        };
+        self.root = match self._delete(key, &agent, storage)? {
+            Some(agent) => {
+                let mut root = Self::_node(&agent, storage)?;
+                if root.red {
+                    root.red = false;
+                    Some(Self::_make(root, storage)?)
+                } else {
+                    Some(agent)
+                }
+            }
+            None => None,
+        };
+        Ok(())
+    }
+
+    fn unload(&mut self) {
+        if let Some(ref root) = self.root {
+            root.borrow_mut().unload();
+        }
+    }
+
+    fn bulk_load<I>(&mut self, pairs: I, storage: &mut impl Storage) -> Result<()>
+    where
+        I: Iterator<Item = (Vec<u8>, Self::Value)>,
+    {
+        let entries = store_sorted::<V, C, _>(pairs, storage)?;
+        // the most levels of 2-3 nodes holding two keys at most each
+        let height = (entries.len() + 1).ilog2();
+        let root = Self::_bulk_load(&entries, height, storage)?;
+        self.root = root.map(|root| rc!(TreeNodeAgent::<V, S>::new(None, Some(root.addr))));
+        Ok(())
+    }
+
+    fn len(&mut self, storage: &mut impl Storage) -> Result<usize> {
+        subtree_size(&self.root, storage)
+    }
+
+    fn rank(&mut self, key: &[u8], inclusive: bool, storage: &mut impl Storage) -> Result<usize> {
+        subtree_rank::<V, S, C>(self.root.clone(), key, inclusive, storage)
+    }
+
+    fn select(&mut self, n: usize, storage: &mut impl Storage) -> Result<Option<Vec<u8>>> {
+        subtree_select(self.root.clone(), n, storage)
+    }
+
+    fn copy(addr: u64, from: &mut impl Storage, to: &mut impl Storage) -> Result<u64> {
+        TreeNodeAgent::<V, S>::copy(addr, from, to)
+    }
+
+    type Cursor = NodeCursor<V, S, C>;
+
+    fn range(&self, range: (Bound<Vec<u8>>, Bound<Vec<u8>>)) -> Self::Cursor {
+        NodeCursor::new(self.root.as_ref().cloned(), range)
+    }
+}
+
+#[cfg(test)]
+mod rb_test {
+    use super::*;
+    use crate::logical_tree::LogicalTree;
+    use crate::storage::FileStorage;
+    use tempfile;
+
+    // check the LLRB properties and sizes, return (black height, size, depth)
+    fn check(
+        agent: &Option<NodeAgentCell<StringAgent, SerdeJson>>,
+        parent_red: bool,
+        storage: &mut FileStorage,
+    ) -> (usize, usize, usize) {
+        match agent {
+            None => (0, 0, 0),
+            Some(agent) => {
+                let node = RedBlackTree::<SerdeJson>::_node(agent, storage).unwrap();
+                assert!(!(parent_red && node.red), "two reds at {:?}", node.key);
+                let (lb, ls, ld) = check(&node.left_agent, node.red, storage);
+                let (rb, rs, rd) = check(&node.right_agent, node.red, storage);
+                assert!(
+                    !RedBlackTree::<SerdeJson>::_is_red(&node.right_agent, storage).unwrap(),
+                    "right leaning red at {:?}",
+                    node.key
+                );
+                assert_eq!(lb, rb, "black unbalanced at {:?}", node.key);
+                assert_eq!(1 + ls + rs, node.size);
+                let black = if node.red { 0 } else { 1 };
+                (lb + black, node.size, 1 + ld.max(rd))
+            }
+        }
+    }
+
+    #[test]
+    fn test_rb_tree_sorted_insert() {
+        let path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+        let mut storage = FileStorage::new(&path).unwrap();
+        let mut tree = RedBlackTree::<SerdeJson>::new().unwrap();
+        for i in 0..1000 {
+            tree.insert(format!("{:04}", i).into(), i.to_string(), &mut storage)
+                .unwrap();
+        }
+        let addr = tree.store(&mut storage).unwrap().unwrap();
+
+        // read the tree back from disk
+        let mut tree = RedBlackTree::<SerdeJson>::new().unwrap();
+        tree.change_view(addr).unwrap();
+        let (_, size, depth) = check(&tree.root, true, &mut storage);
+        assert_eq!(1000, size);
+        // 2 * log2(1000) is less than 20
+        assert!(depth <= 20, "too deep: {}", depth);
+        assert_eq!(
+            Some("567".to_owned()),
+            tree.find(b"0567", &mut storage).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_rb_tree_delete() {
+        let path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+        let mut storage = FileStorage::new(&path).unwrap();
+        let mut tree = RedBlackTree::<SerdeJson>::new().unwrap();
+        for i in 0..200 {
+            tree.insert(
+                format!("{:03}", (i * 7) % 200).into(),
+                i.to_string(),
+                &mut storage,
+            )
+            .unwrap();
+        }
+        for i in (0..200).filter(|i| i % 3 != 0) {
+            tree.delete(format!("{:03}", i).as_bytes(), &mut storage)
+                .unwrap();
+            check(&tree.root, true, &mut storage);
+        }
+        tree.delete(b"nothing", &mut storage).unwrap();
+        let (_, size, _) = check(&tree.root, true, &mut storage);
+        assert_eq!(67, size);
+        assert_eq!(None, tree.find(b"001", &mut storage).unwrap());
+        assert!(tree.find(b"003", &mut storage).unwrap().is_some());
+
+        for i in (0..200).filter(|i| i % 3 == 0) {
+            tree.delete(format!("{:03}", i).as_bytes(), &mut storage)
+                .unwrap();
+        }
+        assert!(tree.root.is_none());
+    }
+
+    #[test]
+    fn test_rb_tree_logical_tree() {
+        let path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+        let mut tree = LogicalTree::<RedBlackTree>::new(&path).unwrap();
+        tree.begin().unwrap();
+        for key in &["a", "b", "c", "d", "e"] {
+            tree.put(key.to_string(), key.to_uppercase()).unwrap();
+        }
+        tree.del("c").unwrap();
+        tree.commit().unwrap();
+        drop(tree);
+
+        let mut tree = LogicalTree::<RedBlackTree>::new(&path).unwrap();
+        assert_eq!(Some("D".to_owned()), tree.get("d").unwrap());
+        assert_eq!(None, tree.get("c").unwrap());
+        let keys: Vec<String> = tree
+            .iter()
+            .unwrap()
+            .map(|pair| String::from_utf8(pair.unwrap().0).unwrap())
+            .collect();
+        assert_eq!(vec!["a", "b", "d", "e"], keys);
+    }
+
+    #[test]
+    fn test_rb_tree_bulk_load() {
+        let path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+        let mut storage = FileStorage::new(&path).unwrap();
+        for n in 0..100 {
+            let mut tree = RedBlackTree::<SerdeJson>::new().unwrap();
+            let pairs = (0..n).map(|i| (format!("{:03}", i).into_bytes(), i.to_string()));
+            tree.bulk_load(pairs, &mut storage).unwrap();
+            let (_, size, depth) = check(&tree.root, true, &mut storage);
+            assert_eq!(n, size);
+            // every black node has a red child at most
+            assert!(depth <= 2 * (n + 1).ilog2() as usize, "too deep: {}", depth);
+            // it goes on like any other tree
+            tree.insert(b"new".to_vec(), "new".to_owned(), &mut storage)
+                .unwrap();
+            tree.delete(b"000", &mut storage).unwrap();
+            check(&tree.root, true, &mut storage);
+        }
+    }
+}
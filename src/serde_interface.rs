@@ -86,3 +86,122 @@ impl SerdeInterface for SerdeBincode {
         Ok(bincode::serialize_into(writer, value)?)
     }
 }
+
+/// Serialize bytes compactly, as raw bytes in binary formats like bincode.
+/// Json would make them an array of numbers, so they become a string if they
+/// are valid UTF-8, or `{"hex": "..."}` if not.
+///
+/// Use it with `#[serde(with = "compact_bytes")]`.
+pub mod compact_bytes {
+    use serde::de::{self, MapAccess, SeqAccess, Visitor};
+    use serde::ser::SerializeMap;
+    use serde::{Deserializer, Serializer};
+    use std::fmt;
+    use std::str;
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        if !serializer.is_human_readable() {
+            return serializer.serialize_bytes(bytes);
+        }
+        match str::from_utf8(bytes) {
+            Ok(s) => serializer.serialize_str(s),
+            Err(_) => {
+                let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+                let mut map = serializer.serialize_map(Some(1))?;
+                map.serialize_entry("hex", &hex)?;
+                map.end()
+            }
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        if deserializer.is_human_readable() {
+            deserializer.deserialize_any(BytesVisitor)
+        } else {
+            deserializer.deserialize_byte_buf(BytesVisitor)
+        }
+    }
+
+    fn from_hex<E: de::Error>(hex: &str) -> Result<Vec<u8>, E> {
+        if hex.len() % 2 != 0 || !hex.is_ascii() {
+            return Err(E::custom("invalid hex bytes"));
+        }
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(E::custom))
+            .collect()
+    }
+
+    struct BytesVisitor;
+
+    impl<'de> Visitor<'de> for BytesVisitor {
+        type Value = Vec<u8>;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "bytes, a string or a hex map")
+        }
+
+        fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Vec<u8>, E> {
+            Ok(v.to_vec())
+        }
+
+        fn visit_byte_buf<E: de::Error>(self, v: Vec<u8>) -> Result<Vec<u8>, E> {
+            Ok(v)
+        }
+
+        fn visit_str<E: de::Error>(self, v: &str) -> Result<Vec<u8>, E> {
+            Ok(v.as_bytes().to_vec())
+        }
+
+        fn visit_string<E: de::Error>(self, v: String) -> Result<Vec<u8>, E> {
+            Ok(v.into_bytes())
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Vec<u8>, A::Error> {
+            let mut bytes = Vec::new();
+            while let Some(b) = seq.next_element()? {
+                bytes.push(b);
+            }
+            Ok(bytes)
+        }
+
+        fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Vec<u8>, A::Error> {
+            match map.next_entry::<String, String>()? {
+                Some((ref key, ref hex)) if key == "hex" => from_hex(hex),
+                _ => Err(de::Error::custom("expect a hex map")),
+            }
+        }
+    }
+}
+
+/// Like `compact_bytes`, for a list of byte strings
+pub mod compact_bytes_seq {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    struct BytesRef<'a>(&'a [u8]);
+
+    impl Serialize for BytesRef<'_> {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            super::compact_bytes::serialize(self.0, serializer)
+        }
+    }
+
+    struct BytesBuf(Vec<u8>);
+
+    impl<'de> Deserialize<'de> for BytesBuf {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            super::compact_bytes::deserialize(deserializer).map(BytesBuf)
+        }
+    }
+
+    pub fn serialize<S: Serializer>(seq: &[Vec<u8>], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(seq.iter().map(|bytes| BytesRef(bytes)))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<Vec<u8>>, D::Error> {
+        let seq = Vec::<BytesBuf>::deserialize(deserializer)?;
+        Ok(seq.into_iter().map(|bytes| bytes.0).collect())
+    }
+}
@@ -0,0 +1,409 @@
+//! A database handle shared between threads.
+//!
+//! `LogicalTree` is made of `Rc<RefCell<...>>`, so it can't leave its
+//! thread. `SharedDb` is `Send + Sync` instead: every thread using it opens a
+//! `LogicalTree` of its own, on the same storage. Reads of different threads
+//! run in parallel, while writers take turns on the lock of the storage,
+//! just like different processes do.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use std::thread;
+//! use dbdb::OpenOptions;
+//!
+//! let db = OpenOptions::new().open_shared("some.db")?;
+//! let handles: Vec<_> = (0..4)
+//!     .map(|i| {
+//!         let db = db.clone();
+//!         thread::spawn(move || db.put(i.to_string(), "done".to_owned()))
+//!     })
+//!     .collect();
+//! for handle in handles {
+//!     handle.join().unwrap()?;
+//! }
+//! ```
+
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Weak};
+
+use anyhow::{anyhow, Result};
+use log::debug;
+
+use crate::logical_tree::{DBTree, LogicalTree, WriteBatch};
+use crate::storage::{FileStorage, Storage};
+
+// gives every SharedDb an id, to find its handles
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+// the LogicalTree of a SharedDb used by this thread
+struct Handle {
+    // gone once every clone of the SharedDb is dropped
+    alive: Weak<()>,
+    // None while it's lent to `SharedDb::with`
+    db: Option<Box<dyn Any>>,
+}
+
+thread_local! {
+    static HANDLES: RefCell<HashMap<usize, Handle>> = RefCell::new(HashMap::new());
+}
+
+type Opener<T, St> = dyn Fn() -> Result<LogicalTree<T, St>> + Send + Sync;
+
+/// A database handle which can be cloned and sent to other threads
+///
+/// Each thread works on its own `LogicalTree`, opened by the first call
+/// made from it. It's closed when the last clone of the `SharedDb` is
+/// dropped, by the thread dropping it, and by other threads the next time
+/// they use any `SharedDb`, or when they exit. A transaction can't span
+/// calls, run it within `SharedDb::transaction` instead.
+///
+/// Calls can't nest: using a `SharedDb` within its own `with` or
+/// `transaction` fails, use the `LogicalTree` given to the closure instead.
+/// Another handle would wait forever for the lock held by the first one.
+pub struct SharedDb<T: DBTree, St: Storage = FileStorage> {
+    id: usize,
+    open: Arc<Opener<T, St>>,
+    // shared by the clones, to tell when they are all dropped
+    alive: Arc<()>,
+}
+
+impl<T, St> SharedDb<T, St>
+where
+    T: DBTree + 'static,
+    St: Storage + 'static,
+{
+    /// Create a shared handle, opening a `LogicalTree` with `open` in every
+    /// thread using it. It's opened once right now, to report errors early.
+    pub fn new<F>(open: F) -> Result<Self>
+    where
+        F: Fn() -> Result<LogicalTree<T, St>> + Send + Sync + 'static,
+    {
+        let db = open()?;
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let alive = Arc::new(());
+        let handle = Handle {
+            alive: Arc::downgrade(&alive),
+            db: Some(Box::new(db)),
+        };
+        HANDLES.with(|handles| handles.borrow_mut().insert(id, handle));
+        Ok(SharedDb {
+            id,
+            open: Arc::new(open),
+            alive,
+        })
+    }
+
+    /// Run `f` with the `LogicalTree` of the current thread. A transaction
+    /// left open by `f` is rolled back.
+    ///
+    /// It fails if this thread is already in a call of this `SharedDb`.
+    pub fn with<R, F>(&self, f: F) -> Result<R>
+    where
+        F: FnOnce(&mut LogicalTree<T, St>) -> Result<R>,
+    {
+        let db = match self.lend()? {
+            Some(db) => db
+                .downcast::<LogicalTree<T, St>>()
+                .map(|db| *db)
+                .map_err(|_| anyhow!("the handle of shared db {} has a wrong type", self.id)),
+            None => {
+                debug!("[SharedDb] open a handle of shared db {}", self.id);
+                (self.open)()
+            }
+        };
+        let mut db = match db {
+            Ok(db) => db,
+            Err(e) => {
+                self.forget();
+                return Err(e);
+            }
+        };
+        let result = f(&mut db);
+        if db.in_transaction() {
+            debug!("[SharedDb] roll back a transaction left open");
+            if let Err(e) = db.rollback() {
+                self.forget();
+                return Err(e);
+            }
+        }
+        HANDLES.with(|handles| {
+            if let Some(handle) = handles.borrow_mut().get_mut(&self.id) {
+                handle.db = Some(Box::new(db));
+            }
+        });
+        result
+    }
+
+    // take the handle of this thread out, None if it has to be opened. It
+    // leaves an empty entry, so that nested calls fail.
+    fn lend(&self) -> Result<Option<Box<dyn Any>>> {
+        HANDLES.with(|handles| {
+            let mut handles = handles.borrow_mut();
+            // close the handles of SharedDb dropped by other threads
+            handles.retain(|_, handle| handle.alive.strong_count() > 0);
+            match handles.get_mut(&self.id) {
+                Some(handle) => handle.db.take().map(Some).ok_or_else(|| {
+                    anyhow!(
+                        "shared db {} is already in use by this thread, \
+                         use the LogicalTree given to the closure",
+                        self.id
+                    )
+                }),
+                None => {
+                    let handle = Handle {
+                        alive: Arc::downgrade(&self.alive),
+                        db: None,
+                    };
+                    handles.insert(self.id, handle);
+                    Ok(None)
+                }
+            }
+        })
+    }
+
+    // drop the entry of this thread, the handle is lost or broken
+    fn forget(&self) {
+        HANDLES.with(|handles| handles.borrow_mut().remove(&self.id));
+    }
+
+    /// Run `f` in a transaction, which is committed if `f` succeeds and
+    /// rolled back otherwise
+    pub fn transaction<R, F>(&self, f: F) -> Result<R>
+    where
+        F: FnOnce(&mut LogicalTree<T, St>) -> Result<R>,
+    {
+        self.with(|db| {
+            db.begin()?;
+            let result = f(db)?;
+            db.commit()?;
+            Ok(result)
+        })
+    }
+
+    /// Run `f` in a read transaction, see `LogicalTree::begin_read`
+    pub fn read_transaction<R, F>(&self, f: F) -> Result<R>
+    where
+        F: FnOnce(&mut LogicalTree<T, St>) -> Result<R>,
+    {
+        self.with(|db| {
+            db.begin_read()?;
+            let result = f(db)?;
+            db.commit()?;
+            Ok(result)
+        })
+    }
+
+    /// Get value by key, see `LogicalTree::get`
+    pub fn get<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<T::Value>> {
+        self.with(|db| db.get(key))
+    }
+
+    /// Put a pair of key:value in a transaction of its own
+    pub fn put<K: Into<Vec<u8>>>(&self, key: K, value: T::Value) -> Result<()> {
+        self.with(|db| db.put(key, value))
+    }
+
+    /// Delete a key in a transaction of its own
+    pub fn del<K: AsRef<[u8]>>(&self, key: K) -> Result<()> {
+        self.with(|db| db.del(key))
+    }
+
+    /// Replace the value of `key` by the result of `f`, see
+    /// `LogicalTree::update`
+    pub fn update<K, F>(&self, key: K, f: F) -> Result<Option<T::Value>>
+    where
+        K: Into<Vec<u8>>,
+        F: FnOnce(Option<T::Value>) -> Option<T::Value>,
+        T::Value: Clone,
+    {
+        self.with(|db| db.update(key, f))
+    }
+
+    /// Apply a batch of puts and deletes at once, see `LogicalTree::write`
+    pub fn write(&self, batch: WriteBatch<T::Value>) -> Result<()> {
+        self.with(|db| db.write(batch))
+    }
+
+    /// Write only if the value of `key` is `expected`, see
+    /// `LogicalTree::compare_and_swap`
+    pub fn compare_and_swap<K: Into<Vec<u8>>>(
+        &self,
+        key: K,
+        expected: Option<T::Value>,
+        new: Option<T::Value>,
+    ) -> Result<bool>
+    where
+        T::Value: PartialEq,
+    {
+        self.with(|db| db.compare_and_swap(key, expected, new))
+    }
+
+    /// Put a pair of key:value only if the key is missing, see
+    /// `LogicalTree::put_if_absent`
+    pub fn put_if_absent<K: Into<Vec<u8>>>(&self, key: K, value: T::Value) -> Result<bool>
+    where
+        T::Value: PartialEq,
+    {
+        self.with(|db| db.put_if_absent(key, value))
+    }
+
+    /// Delete a key only if its value is `expected`, see
+    /// `LogicalTree::delete_if_equals`
+    pub fn delete_if_equals<K: Into<Vec<u8>>>(&self, key: K, expected: T::Value) -> Result<bool>
+    where
+        T::Value: PartialEq,
+    {
+        self.with(|db| db.delete_if_equals(key, expected))
+    }
+}
+
+impl<T: DBTree, St: Storage> Clone for SharedDb<T, St> {
+    fn clone(&self) -> Self {
+        SharedDb {
+            id: self.id,
+            open: self.open.clone(),
+            alive: self.alive.clone(),
+        }
+    }
+}
+
+impl<T: DBTree, St: Storage> Drop for SharedDb<T, St> {
+    fn drop(&mut self) {
+        // the last clone closes the handle of this thread, the thread-local
+        // may be gone already if the thread is exiting
+        if Arc::strong_count(&self.alive) == 1 {
+            let _ = HANDLES.try_with(|handles| {
+                if let Ok(mut handles) = handles.try_borrow_mut() {
+                    handles.remove(&self.id);
+                }
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod shared_test {
+    use super::{SharedDb, HANDLES};
+    use crate::db::{LockPolicy, OpenOptions};
+    use crate::logical_tree::{BinaryTree, LogicalTree};
+    use crate::storage::MemStorage;
+    use std::sync::mpsc;
+    use std::thread;
+    use tempfile;
+
+    fn assert_send_sync<T: Send + Sync>(_: &T) {}
+
+    #[test]
+    fn test_shared_db_threads() {
+        let path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+        let db = OpenOptions::new().open_shared(&path).unwrap();
+        assert_send_sync(&db);
+        db.put("count", "0".to_owned()).unwrap();
+
+        let handles: Vec<_> = (0..8)
+            .map(|i| {
+                let db = db.clone();
+                thread::spawn(move || {
+                    db.put(format!("key{}", i), i.to_string()).unwrap();
+                    // writers take turns, no increment is lost
+                    db.transaction(|tree| {
+                        let count: u32 = tree.get("count")?.unwrap().parse()?;
+                        tree.put("count", (count + 1).to_string())
+                    })
+                    .unwrap();
+                    db.get(format!("key{}", i)).unwrap()
+                })
+            })
+            .collect();
+        for (i, handle) in handles.into_iter().enumerate() {
+            assert_eq!(Some(i.to_string()), handle.join().unwrap());
+        }
+        assert_eq!(Some("8".to_owned()), db.get("count").unwrap());
+
+        // a failed transaction leaves nothing behind
+        assert!(db
+            .transaction(|tree| {
+                tree.put("count", "100".to_owned())?;
+                tree.get("missing")?
+                    .ok_or_else(|| anyhow::anyhow!("missing"))
+            })
+            .is_err());
+        assert_eq!(Some("8".to_owned()), db.get("count").unwrap());
+
+        // a single thread claims the job
+        let handles: Vec<_> = (0..8)
+            .map(|i| {
+                let db = db.clone();
+                thread::spawn(move || db.put_if_absent("job", i.to_string()).unwrap())
+            })
+            .collect();
+        let claims = handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .filter(|&claimed| claimed)
+            .count();
+        assert_eq!(1, claims);
+    }
+
+    #[test]
+    fn test_shared_db_mem_storage() {
+        let storage = MemStorage::new();
+        let db = SharedDb::new(move || {
+            LogicalTree::<BinaryTree, _>::with_storage(storage.clone(), LockPolicy::Wait)
+        })
+        .unwrap();
+        let writer = db.clone();
+        thread::spawn(move || writer.put("a", "1".to_owned()).unwrap())
+            .join()
+            .unwrap();
+        assert_eq!(Some("1".to_owned()), db.get("a").unwrap());
+        // nested use fails instead of waiting for itself
+        assert!(db
+            .transaction(|tree| {
+                tree.put("b", "2".to_owned())?;
+                db.put("c", "3".to_owned())
+            })
+            .is_err());
+        assert_eq!(None, db.get("b").unwrap());
+        assert_eq!(Some("1".to_owned()), db.with(|tree| tree.get("a")).unwrap());
+    }
+
+    #[test]
+    fn test_shared_db_handles_closed() {
+        let handles = || HANDLES.with(|handles| handles.borrow().len());
+        let storage = MemStorage::new();
+        let open =
+            move || LogicalTree::<BinaryTree, _>::with_storage(storage.clone(), LockPolicy::Wait);
+        let kept = SharedDb::new(open.clone()).unwrap();
+        let db = SharedDb::new(open).unwrap();
+        let clone = db.clone();
+        drop(db);
+        assert_eq!(2, handles());
+        let (dropped, wait_dropped) = mpsc::channel();
+        let (go_on, wait_go_on) = mpsc::channel();
+        let (kept_clone, db_clone) = (kept.clone(), clone.clone());
+        let thread = thread::spawn(move || {
+            kept_clone.get("a").unwrap();
+            db_clone.get("a").unwrap();
+            let opened = handles();
+            drop(db_clone);
+            dropped.send(()).unwrap();
+            wait_go_on.recv().unwrap();
+            // the next use finds the other db dropped
+            kept_clone.get("a").unwrap();
+            (opened, handles())
+        });
+        wait_dropped.recv().unwrap();
+        // the last clone closes the handle of its own thread at once
+        drop(clone);
+        assert_eq!(1, handles());
+        go_on.send(()).unwrap();
+        assert_eq!((2, 1), thread.join().unwrap());
+        drop(kept);
+        assert_eq!(0, handles());
+    }
+}
@@ -1,19 +1,34 @@
 //! Append-only storage for an immutable tree.
 //!
+//! Every record, including the superblock, is framed as
+//! `[length: u32][crc32: u32][data]`, so that corruption is detected on load.
+//! The superblock starts with `b"DBDB"` and the `FORMAT_VERSION` of the file,
+//! files of other formats fail with a `FormatError`.
+//!
+//! `FileStorage` keeps the data in a file, `MemStorage` in a `Vec<u8>`.
+use crate::cache::{cache_size, HeapSize, NodeCache};
 use crate::serde_interface::{SerdeBincode, SerdeInterface};
 
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use std::error::Error;
+use std::fmt;
 
-use std::fs::{File, OpenOptions};
+use std::fs::{self, File, OpenOptions};
 use std::io::{Read, Seek, SeekFrom, Write};
 use std::ops::{Deref, DerefMut};
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-// use log::{debug, info};
+use log::debug;
 
 use anyhow::{Context, Result};
 
-use cluFlock::{element::FlockElement, ExclusiveFlock, FlockLock};
+use crc32fast::Hasher;
+
+use cluFlock::{element::FlockElement, ExclusiveFlock, FlockLock, SharedFlock};
 
 #[cfg(unix)]
 use std::os::unix::io::{AsRawFd, RawFd};
@@ -21,10 +36,180 @@ use std::os::unix::io::{AsRawFd, RawFd};
 use std::os::windows::io::{AsRawHandle, RawHandle};
 
 const SUPERBLOCK: u64 = 512;
+const RECORD_HEADER: u64 = 8;
+// the superblock starts with them, followed by the framed meta
+const MAGIC: [u8; 4] = *b"DBDB";
+const FORMAT_HEADER: u64 = 8;
+
+/// The version of the file format, bump it whenever the records or the
+/// superblock change in a way older files can't be read:
+/// 1. checksummed records
+/// 2. commit records, chained from the superblock
+/// 3. the root is a catalog of buckets, in the format of the trees, and the
+///    superblock names the comparator
+pub const FORMAT_VERSION: u32 = 3;
+
+/// The file isn't a database of `FORMAT_VERSION`, e.g. it was written by
+/// another version of this crate, or it isn't a database at all
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FormatError {
+    /// The format version of the file, None if it has no magic number
+    pub found: Option<u32>,
+}
+
+impl fmt::Display for FormatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.found {
+            Some(version) => write!(
+                f,
+                "file format version {} can't be opened, expect version {}",
+                version, FORMAT_VERSION
+            ),
+            None => write!(
+                f,
+                "not a database file, or of a format older than version 1"
+            ),
+        }
+    }
+}
+
+impl Error for FormatError {}
+
+/// A record doesn't match its checksum, or its length runs out of the file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CorruptionError {
+    /// Where the bad record starts
+    pub addr: u64,
+}
+
+impl fmt::Display for CorruptionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "corrupted record at addr {}", self.addr)
+    }
+}
+
+impl Error for CorruptionError {}
+
+/// The lock of a storage is held by someone else, returned by the `try_`
+/// ways of locking
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LockedError;
+
+impl fmt::Display for LockedError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "storage is locked")
+    }
+}
+
+impl Error for LockedError {}
+
+// tell a lock held by someone else from other errors of flock
+fn lock_error(e: std::io::Error) -> anyhow::Error {
+    if e.kind() == std::io::ErrorKind::WouldBlock {
+        LockedError.into()
+    } else {
+        e.into()
+    }
+}
+
+fn checksum(data: &[u8]) -> u32 {
+    let mut hasher = Hasher::new();
+    hasher.update(data);
+    hasher.finalize()
+}
+
+// prefix data with its length and checksum
+fn frame(data: &[u8]) -> Vec<u8> {
+    let mut record = Vec::with_capacity(RECORD_HEADER as usize + data.len());
+    record.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    record.extend_from_slice(&checksum(data).to_le_bytes());
+    record.extend_from_slice(data);
+    record
+}
+
+// read a framed record at `addr`, which is at most `limit` bytes long
+fn read_frame<R: Read + ?Sized>(reader: &mut R, addr: u64, limit: u64) -> Result<Vec<u8>> {
+    let mut header = [0u8; RECORD_HEADER as usize];
+    reader
+        .read_exact(&mut header)
+        .map_err(|_| CorruptionError { addr })?;
+    let mut word = [0u8; 4];
+    word.copy_from_slice(&header[..4]);
+    let len = u32::from_le_bytes(word) as u64;
+    word.copy_from_slice(&header[4..]);
+    let crc = u32::from_le_bytes(word);
+    if RECORD_HEADER + len > limit {
+        return Err(CorruptionError { addr }.into());
+    }
+    let mut data = vec![0u8; len as usize];
+    reader
+        .read_exact(&mut data)
+        .map_err(|_| CorruptionError { addr })?;
+    if checksum(&data) != crc {
+        return Err(CorruptionError { addr }.into());
+    }
+    Ok(data)
+}
+
+/// How hard `commit_root_addr` tries to make a commit survive a power loss
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncMode {
+    /// fsync the nodes, write the root, then fsync again. A commit is durable
+    /// once it returns.
+    Full,
+    /// fsync the nodes before writing the root. A crash may lose the last
+    /// commits, but the root never points to bytes missing on disk.
+    Data,
+    /// Leave it to the OS. A crash may corrupt the file.
+    None,
+}
 
 pub trait Storage: Write + Read + Seek {
-    /// Block until we acquire an advisory lock of the current storage.
-    fn lock(&self) -> Result<FileStorageGuard>;
+    /// The access right of the storage, released when dropped
+    type Guard;
+
+    /// Block until we acquire an exclusive advisory lock of the current
+    /// storage, for writing.
+    fn lock(&mut self) -> Result<Self::Guard>;
+
+    /// Try to acquire an exclusive advisory lock of the current storage,
+    /// failing immediately with a `LockedError` if someone else holds any
+    /// lock.
+    fn try_lock(&mut self) -> Result<Self::Guard>;
+
+    /// Like `try_lock`, but keep trying for `timeout` before failing with a
+    /// `LockedError`.
+    fn lock_timeout(&mut self, timeout: Duration) -> Result<Self::Guard> {
+        let deadline = Instant::now() + timeout;
+        // poll, since flock can't wait for a limited time
+        let mut pause = Duration::from_millis(1);
+        loop {
+            match self.try_lock() {
+                Err(e) if e.downcast_ref::<LockedError>().is_some() => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(e);
+                    }
+                    thread::sleep(pause.min(deadline - now));
+                    pause = (pause * 2).min(Duration::from_millis(50));
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Block until we acquire a shared advisory lock of the current storage,
+    /// for reading. Many readers can hold it at once, but never along with a
+    /// writer.
+    ///
+    /// Every shared guard is released only when it's dropped, even if this
+    /// storage takes and releases other locks. So an exclusive lock of this
+    /// storage waits for them like for anyone else's.
+    fn lock_shared(&mut self) -> Result<Self::Guard>;
+
+    /// Try to acquire a shared advisory lock of the current storage, failing
+    /// immediately with a `LockedError` if a writer holds the lock.
+    fn try_lock_shared(&mut self) -> Result<Self::Guard>;
 
     /// Get the address where the next write will happen.
     fn get_write_addr(&mut self) -> Result<u64>;
@@ -32,8 +217,122 @@ pub trait Storage: Write + Read + Seek {
     /// Get the address of the current root node.
     fn get_root_addr(&mut self) -> Result<Option<u64>>;
 
-    /// Commit the addr of the new root node
+    /// Get the address of the latest commit record.
+    fn get_commit_addr(&mut self) -> Result<Option<u64>>;
+
+    /// Get the name of the `Comparator` the keys are ordered by, None if it
+    /// isn't recorded yet.
+    fn get_comparator(&mut self) -> Result<Option<String>>;
+
+    /// Record the name of the `Comparator` the keys are ordered by. Hold the
+    /// lock while using it.
+    fn set_comparator(&mut self, name: &str) -> Result<()>;
+
+    /// Commit the addr of the new root node, as a new version. Everything
+    /// written before is made durable first, according to the sync mode of
+    /// the storage.
     fn commit_root_addr(&mut self, addr: u64) -> Result<()>;
+
+    /// Create an empty storage of the same kind, to receive a compacted copy
+    /// of the data. Its versions continue from ours. Hold the lock while
+    /// using it.
+    fn create_compacted(&mut self) -> Result<Self>
+    where
+        Self: Sized;
+
+    /// Replace this storage with `compacted`, which is created by
+    /// `create_compacted`. Hold the lock while using it.
+    fn replace_with(&mut self, compacted: Self) -> Result<()>
+    where
+        Self: Sized;
+
+    /// Count the times the storage moved to another file, e.g. a compacted
+    /// one. Addresses read before lead nowhere once it changes.
+    fn generation(&self) -> u64;
+
+    /// Append `value` serialized by `S` as a checksummed record, return its
+    /// addr.
+    fn write_record<S, T>(&mut self, value: &T) -> Result<u64>
+    where
+        S: SerdeInterface,
+        T: Serialize,
+    {
+        let mut data = Vec::new();
+        S::to_writer(&mut data, value)?;
+        let addr = self.get_write_addr()?;
+        self.write_all(&frame(&data))?;
+        Ok(addr)
+    }
+
+    /// Get every commit kept in the storage, the latest first
+    fn get_commits(&mut self) -> Result<Vec<Commit>> {
+        let mut commits = Vec::new();
+        let mut addr = self.get_commit_addr()?;
+        while let Some(commit_addr) = addr {
+            let commit: Commit = self.read_record::<SerdeBincode, _>(commit_addr)?;
+            addr = commit.prev_addr;
+            commits.push(commit);
+        }
+        Ok(commits)
+    }
+
+    /// Get the commit of `version`, None if it isn't kept
+    fn get_commit(&mut self, version: u64) -> Result<Option<Commit>> {
+        let mut addr = self.get_commit_addr()?;
+        while let Some(commit_addr) = addr {
+            let commit: Commit = self.read_record::<SerdeBincode, _>(commit_addr)?;
+            // the versions only decrease along the chain
+            if commit.version <= version {
+                return Ok(Some(commit).filter(|commit| commit.version == version));
+            }
+            addr = commit.prev_addr;
+        }
+        Ok(None)
+    }
+
+    /// Load the record at `addr` and deserialize it by `S`. Fail with a
+    /// `CorruptionError` if it doesn't match its checksum.
+    fn read_record<S, T>(&mut self, addr: u64) -> Result<T>
+    where
+        S: SerdeInterface,
+        T: DeserializeOwned,
+    {
+        let data = read_data(self, addr)?;
+        S::from_reader(&data[..])
+    }
+
+    /// The cache of tree nodes read from this storage, None if it has none
+    fn node_cache(&mut self) -> Option<&mut NodeCache> {
+        None
+    }
+
+    /// Like `read_record`, but look for the node in the node cache first,
+    /// and keep it there once loaded
+    fn read_node<S, T>(&mut self, addr: u64) -> Result<T>
+    where
+        S: SerdeInterface,
+        T: DeserializeOwned + HeapSize + Clone + Send + Sync + 'static,
+    {
+        if let Some(node) = self.node_cache().and_then(|cache| cache.get::<T>(addr)) {
+            return Ok(node);
+        }
+        let data = read_data(self, addr)?;
+        let node: T = S::from_reader(&data[..])?;
+        if let Some(cache) = self.node_cache() {
+            cache.insert(addr, node.clone(), cache_size(&node));
+        }
+        Ok(node)
+    }
+}
+
+// read the data of the record at `addr`
+fn read_data<St: Storage + ?Sized>(storage: &mut St, addr: u64) -> Result<Vec<u8>> {
+    let end = storage.seek(SeekFrom::End(0))?;
+    if addr >= end {
+        return Err(CorruptionError { addr }.into());
+    }
+    let _ = storage.seek(SeekFrom::Start(addr))?;
+    read_frame(storage, addr, end - addr)
 }
 
 /// The underlying storage of an immutable tree structure
@@ -48,9 +347,12 @@ pub trait Storage: Write + Read + Seek {
 pub struct FileStorage {
     path: PathBuf,
     file: File,
+    sync: SyncMode,
+    cache: NodeCache,
+    generation: u64,
 }
 
-/// Manage the exculsive access right of the storage
+/// Manage the exculsive or shared access right of the storage
 ///
 /// `FileStorageGuard` implements `DerefMut<Target=FileStorage>` trait, so
 /// you can use it like `Box<FileStorage>`. When `FileStorageGuard` is dropped,
@@ -59,9 +361,51 @@ pub struct FileStorageGuard {
     inner: FlockLock<FileStorage>,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Default)]
 struct Meta {
     root_addr: Option<u64>,
+    // the latest commit record, which leads to the previous ones
+    commit_addr: Option<u64>,
+    version: u64,
+    // the file has been replaced by a compacted one, reopen the path to
+    // find it
+    moved: bool,
+    // the name of the comparator ordering the keys
+    comparator: Option<String>,
+}
+
+/// A commit record. Every commit appends one, linked to the previous one, so
+/// that older versions of the tree can still be found.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Commit {
+    /// Starts from 1, and increases by one with every commit
+    pub version: u64,
+    /// The root committed in this version, None if it's empty
+    pub root_addr: Option<u64>,
+    /// Seconds since the unix epoch
+    pub timestamp: u64,
+    prev_addr: Option<u64>,
+}
+
+impl Meta {
+    // the commit record following the current one
+    fn next_commit(&self, addr: u64) -> Commit {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        Commit {
+            version: self.version + 1,
+            root_addr: if addr == 0 { None } else { Some(addr) },
+            timestamp,
+            prev_addr: self.commit_addr,
+        }
+    }
+
+    fn apply(&mut self, commit: &Commit, commit_addr: u64) {
+        self.root_addr = commit.root_addr;
+        self.commit_addr = Some(commit_addr);
+        self.version = commit.version;
+    }
 }
 
 impl FileStorageGuard {
@@ -69,6 +413,27 @@ impl FileStorageGuard {
         let inner = ExclusiveFlock::wait_lock(file_store).map_err(|e| e.err())?;
         Ok(FileStorageGuard { inner })
     }
+
+    pub fn try_new(file_store: FileStorage) -> Result<Self> {
+        let path = file_store.path.clone();
+        let inner = ExclusiveFlock::try_lock(file_store)
+            .map_err(|e| lock_error(e.err()))
+            .with_context(|| format!("storage file {:?} is locked", path))?;
+        Ok(FileStorageGuard { inner })
+    }
+
+    pub fn new_shared(file_store: FileStorage) -> Result<Self> {
+        let inner = SharedFlock::wait_lock(file_store).map_err(|e| e.err())?;
+        Ok(FileStorageGuard { inner })
+    }
+
+    pub fn try_new_shared(file_store: FileStorage) -> Result<Self> {
+        let path = file_store.path.clone();
+        let inner = SharedFlock::try_lock(file_store)
+            .map_err(|e| lock_error(e.err()))
+            .with_context(|| format!("storage file {:?} is locked", path))?;
+        Ok(FileStorageGuard { inner })
+    }
 }
 
 impl Deref for FileStorageGuard {
@@ -130,26 +495,41 @@ impl FlockElement for FileStorage {
 impl FileStorage {
     /// Open the file, write superblock matadata and return a `FileStorage`
     pub fn new<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::open(path, true)
+    }
+
+    /// Like `FileStorage::new`, but fail if the file is missing and `create`
+    /// is false
+    pub fn open<P: AsRef<Path>>(path: P, create: bool) -> Result<Self> {
         let path = PathBuf::from(path.as_ref());
         let file = OpenOptions::new()
             .read(true)
             .write(true)
-            .create(true)
+            .create(create)
+            .truncate(false)
             .open(&path)
             .with_context(|| format!("can't open storage file {:?}", path))?;
 
-        let mut storage = FileStorage { path, file };
+        let mut storage = FileStorage {
+            path,
+            file,
+            sync: SyncMode::Full,
+            cache: NodeCache::default(),
+            generation: 0,
+        };
         storage.ensure_superblock()?;
         Ok(storage)
     }
 
     fn ensure_superblock(&mut self) -> Result<()> {
-        let mut guard = self.lock()?;
+        // the file may be too short to have a superblock, so don't check
+        // whether it is moved
+        let mut guard = FileStorageGuard::new(self.try_clone()?)?;
         let end_idx = guard.seek(SeekFrom::End(0))?;
         if end_idx < SUPERBLOCK {
             // init the db file
             guard.write_all(&vec![0; SUPERBLOCK as usize])?;
-            guard.commit_root_addr(0)?;
+            guard.write_meta(&Meta::default())?;
         }
         Ok(())
     }
@@ -158,13 +538,136 @@ impl FileStorage {
         Ok(FileStorage {
             path: self.path.clone(),
             file: self.file.try_clone()?,
+            sync: self.sync,
+            cache: NodeCache::new(self.cache.budget()),
+            generation: self.generation,
+        })
+    }
+
+    /// Choose how commits are synced to disk, `SyncMode::Full` by default
+    pub fn set_sync(&mut self, sync: SyncMode) {
+        self.sync = sync;
+    }
+
+    /// Choose how many bytes of decoded nodes are cached by this handle,
+    /// `DEFAULT_CACHE_BUDGET` by default
+    pub fn set_cache_budget(&mut self, budget: usize) {
+        self.cache.set_budget(budget);
+    }
+
+    fn read_meta(&mut self) -> Result<Meta> {
+        let _ = self.file.seek(SeekFrom::Start(0))?;
+        let mut header = [0u8; FORMAT_HEADER as usize];
+        self.file.read_exact(&mut header)?;
+        if header[..4] != MAGIC {
+            return Err(FormatError { found: None }.into());
+        }
+        let mut word = [0u8; 4];
+        word.copy_from_slice(&header[4..]);
+        let version = u32::from_le_bytes(word);
+        if version != FORMAT_VERSION {
+            return Err(FormatError {
+                found: Some(version),
+            }
+            .into());
+        }
+        let data = read_frame(&mut self.file, 0, SUPERBLOCK - FORMAT_HEADER)?;
+        SerdeBincode::from_reader(&data[..])
+    }
+
+    // read the meta, following the path if the file was compacted
+    fn current_meta(&mut self) -> Result<Meta> {
+        let mut meta = self.read_meta()?;
+        while meta.moved {
+            self.reopen()?;
+            meta = self.read_meta()?;
+        }
+        Ok(meta)
+    }
+
+    fn write_meta(&mut self, meta: &Meta) -> Result<()> {
+        let mut data = Vec::new();
+        SerdeBincode::to_writer(&mut data, meta)?;
+        let mut superblock = MAGIC.to_vec();
+        superblock.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+        superblock.extend_from_slice(&frame(&data));
+        let _ = self.file.seek(SeekFrom::Start(0))?;
+        self.file.write_all(&superblock)?;
+        Ok(())
+    }
+
+    // follow the path to the compacted file
+    fn reopen(&mut self) -> Result<()> {
+        debug!("[Storage] {:?} was compacted, reopen it", self.path);
+        let reopened = FileStorage::open(&self.path, false)?;
+        self.file = reopened.file;
+        // the addresses lead to the compacted file now
+        self.cache.clear();
+        self.generation += 1;
+        Ok(())
+    }
+
+    // lock the file by `new_guard`, the file may be compacted while we waited
+    fn lock_by(
+        &mut self,
+        new_guard: fn(FileStorage) -> Result<FileStorageGuard>,
+        shared: bool,
+    ) -> Result<FileStorageGuard> {
+        loop {
+            // flock belongs to the open file, and is released by any of its
+            // clones. A shared lock opens the file again, to be released
+            // only with its own guard.
+            let file = if shared {
+                self.open_again()?
+            } else {
+                self.try_clone()?
+            };
+            let mut guard = new_guard(file)?;
+            if !guard.read_meta()?.moved && !self.read_meta()?.moved {
+                return Ok(guard);
+            }
+            drop(guard);
+            self.reopen()?;
+        }
+    }
+
+    fn open_again(&self) -> Result<FileStorage> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&self.path)
+            .with_context(|| format!("can't open storage file {:?}", self.path))?;
+        Ok(FileStorage {
+            path: self.path.clone(),
+            file,
+            sync: self.sync,
+            cache: NodeCache::new(self.cache.budget()),
+            generation: self.generation,
         })
     }
 }
 
 impl Storage for FileStorage {
-    fn lock(&self) -> Result<FileStorageGuard> {
-        FileStorageGuard::new(self.try_clone()?)
+    type Guard = FileStorageGuard;
+
+    fn lock(&mut self) -> Result<FileStorageGuard> {
+        self.lock_by(FileStorageGuard::new, false)
+    }
+
+    fn try_lock(&mut self) -> Result<FileStorageGuard> {
+        self.lock_by(FileStorageGuard::try_new, false)
+    }
+
+    fn lock_shared(&mut self) -> Result<FileStorageGuard> {
+        self.lock_by(FileStorageGuard::new_shared, true)
+    }
+
+    fn try_lock_shared(&mut self) -> Result<FileStorageGuard> {
+        self.lock_by(FileStorageGuard::try_new_shared, true)
+    }
+
+    fn node_cache(&mut self) -> Option<&mut NodeCache> {
+        Some(&mut self.cache)
     }
 
     fn get_write_addr(&mut self) -> Result<u64> {
@@ -173,28 +676,396 @@ impl Storage for FileStorage {
     }
 
     fn get_root_addr(&mut self) -> Result<Option<u64>> {
-        let _ = self.seek(SeekFrom::Start(0))?;
-        let meta: Meta = SerdeBincode::from_reader(self)?;
-        Ok(meta.root_addr)
+        Ok(self.current_meta()?.root_addr)
+    }
+
+    fn get_commit_addr(&mut self) -> Result<Option<u64>> {
+        Ok(self.current_meta()?.commit_addr)
+    }
+
+    fn get_comparator(&mut self) -> Result<Option<String>> {
+        Ok(self.current_meta()?.comparator)
+    }
+
+    fn set_comparator(&mut self, name: &str) -> Result<()> {
+        let mut meta = self.read_meta()?;
+        meta.comparator = Some(name.to_owned());
+        self.write_meta(&meta)?;
+        if self.sync != SyncMode::None {
+            self.file.sync_data()?;
+        }
+        Ok(())
     }
 
     fn commit_root_addr(&mut self, addr: u64) -> Result<()> {
-        self.seek(SeekFrom::Start(0))?;
-        let meta = if addr == 0 {
-            Meta { root_addr: None }
+        let mut meta = self.read_meta()?;
+        let commit = meta.next_commit(addr);
+        let commit_addr = self.write_record::<SerdeBincode, _>(&commit)?;
+        // the nodes must reach the disk before the root pointing to them
+        if self.sync != SyncMode::None {
+            self.file.sync_data()?;
+        }
+        meta.apply(&commit, commit_addr);
+        self.write_meta(&meta)?;
+        if self.sync == SyncMode::Full {
+            self.file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    /// The compacted file is created next to this one, with a `.compact`
+    /// suffix.
+    fn create_compacted(&mut self) -> Result<FileStorage> {
+        let mut path = self.path.clone().into_os_string();
+        path.push(".compact");
+        let _ = fs::remove_file(&path);
+        let mut compacted = FileStorage::new(path)?;
+        let meta = self.read_meta()?;
+        compacted.write_meta(&Meta {
+            version: meta.version,
+            comparator: meta.comparator,
+            ..Meta::default()
+        })?;
+        Ok(compacted)
+    }
+
+    /// The compacted file is renamed to our path, then the old file is marked
+    /// as moved, so that other handles still reading or waiting for the lock
+    /// of the old file know it's time to reopen the path.
+    fn replace_with(&mut self, compacted: FileStorage) -> Result<()> {
+        compacted.file.sync_all()?;
+        fs::rename(&compacted.path, &self.path)
+            .with_context(|| format!("can't replace storage file {:?}", self.path))?;
+        // else a crash may bring the old file back under the path
+        if self.sync != SyncMode::None {
+            sync_dir(&self.path)?;
+        }
+        let mut meta = self.read_meta()?;
+        meta.moved = true;
+        self.write_meta(&meta)?;
+        self.file = compacted.file;
+        self.cache.clear();
+        self.generation += 1;
+        debug!("[Storage] {:?} is replaced by a compacted file", self.path);
+        Ok(())
+    }
+
+    fn generation(&self) -> u64 {
+        self.generation
+    }
+}
+
+// fsync the directory holding `path`, to make a rename in it durable
+#[cfg(unix)]
+fn sync_dir(path: &Path) -> Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)
+        .and_then(|dir| dir.sync_all())
+        .with_context(|| format!("can't sync directory {:?}", dir))
+}
+
+// windows can't open a directory as a file to sync it
+#[cfg(windows)]
+fn sync_dir(_path: &Path) -> Result<()> {
+    Ok(())
+}
+
+// the memory version of a file
+struct MemFile {
+    data: Vec<u8>,
+    meta: Meta,
+}
+
+// who holds the lock of a MemStorage
+#[derive(Default)]
+struct MemLockState {
+    writer: bool,
+    readers: usize,
+}
+
+// the lock state and a condvar to wait for it
+type MemLock = Arc<(Mutex<MemLockState>, Condvar)>;
+
+/// A storage in memory, for tests and ephemeral data
+///
+/// Clones of a `MemStorage` share the same data and lock, like several
+/// `FileStorage` opening the same path.
+///
+/// # Examples
+/// ```no_run
+/// let mut tree = LogicalTree::<BinaryTree, _>::with_storage(MemStorage::new(), LockPolicy::Wait)?;
+/// tree.put("answer".to_owned(), "42".to_owned())?;
+/// ```
+pub struct MemStorage {
+    // like a path, it always leads to the current file
+    path: Arc<Mutex<Arc<Mutex<MemFile>>>>,
+    file: Arc<Mutex<MemFile>>,
+    lock: MemLock,
+    pos: u64,
+    cache: NodeCache,
+    generation: u64,
+}
+
+/// Manage the exclusive or shared access right of a `MemStorage`. The lock
+/// is released when it is dropped.
+pub struct MemStorageGuard {
+    lock: MemLock,
+    shared: bool,
+}
+
+impl Drop for MemStorageGuard {
+    fn drop(&mut self) {
+        let (state, cvar) = &*self.lock;
+        let mut state = state.lock().unwrap();
+        if self.shared {
+            state.readers -= 1;
         } else {
-            Meta {
-                root_addr: Some(addr),
+            state.writer = false;
+        }
+        cvar.notify_all();
+    }
+}
+
+impl MemStorage {
+    /// Create an empty storage
+    pub fn new() -> Self {
+        let file = Arc::new(Mutex::new(MemFile::new()));
+        MemStorage {
+            path: Arc::new(Mutex::new(file.clone())),
+            file,
+            lock: Arc::new((Mutex::new(MemLockState::default()), Condvar::new())),
+            pos: 0,
+            cache: NodeCache::default(),
+            generation: 0,
+        }
+    }
+
+    /// Choose how many bytes of decoded nodes are cached by this handle,
+    /// `DEFAULT_CACHE_BUDGET` by default
+    pub fn set_cache_budget(&mut self, budget: usize) {
+        self.cache.set_budget(budget);
+    }
+
+    fn file(&self) -> MutexGuard<'_, MemFile> {
+        self.file.lock().unwrap()
+    }
+
+    // follow the path to the compacted file
+    fn reopen(&mut self) {
+        debug!("[Storage] memory storage was compacted, reopen it");
+        self.file = self.path.lock().unwrap().clone();
+        self.cache.clear();
+        self.generation += 1;
+    }
+
+    fn is_moved(&self) -> bool {
+        self.file().meta.moved
+    }
+
+    // take a shared or exclusive lock, waiting for it if `wait`, or
+    // failing at once otherwise
+    fn lock_by(&mut self, shared: bool, wait: bool) -> Result<MemStorageGuard> {
+        {
+            let (state, cvar) = &*self.lock;
+            let mut state = state.lock().unwrap();
+            loop {
+                let free = !state.writer && (shared || state.readers == 0);
+                if free {
+                    break;
+                }
+                if !wait {
+                    return Err(LockedError.into());
+                }
+                state = cvar.wait(state).unwrap();
+            }
+            if shared {
+                state.readers += 1;
+            } else {
+                state.writer = true;
             }
+        }
+        // the file may be compacted while we waited
+        if self.is_moved() {
+            self.reopen();
+        }
+        Ok(MemStorageGuard {
+            lock: self.lock.clone(),
+            shared,
+        })
+    }
+}
+
+impl MemFile {
+    fn new() -> Self {
+        // keep the addr 0 unused, it means no root
+        MemFile {
+            data: vec![0; SUPERBLOCK as usize],
+            meta: Meta::default(),
+        }
+    }
+}
+
+impl Default for MemStorage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clone for MemStorage {
+    fn clone(&self) -> Self {
+        MemStorage {
+            path: self.path.clone(),
+            file: self.file.clone(),
+            lock: self.lock.clone(),
+            pos: 0,
+            cache: NodeCache::new(self.cache.budget()),
+            generation: self.generation,
+        }
+    }
+}
+
+impl Write for MemStorage {
+    fn write(&mut self, data: &[u8]) -> Result<usize, std::io::Error> {
+        // append only
+        self.file().data.extend_from_slice(data);
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> Result<(), std::io::Error> {
+        Ok(())
+    }
+}
+
+impl Read for MemStorage {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, std::io::Error> {
+        let n = {
+            let file = self.file();
+            let start = (self.pos as usize).min(file.data.len());
+            let n = buf.len().min(file.data.len() - start);
+            buf[..n].copy_from_slice(&file.data[start..start + n]);
+            n
         };
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for MemStorage {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64, std::io::Error> {
+        let len = self.file().data.len() as i64;
+        let pos = match pos {
+            SeekFrom::Start(pos) => pos as i64,
+            SeekFrom::End(offset) => len + offset,
+            SeekFrom::Current(offset) => self.pos as i64 + offset,
+        };
+        if pos < 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "seek to a negative position",
+            ));
+        }
+        self.pos = pos as u64;
+        Ok(self.pos)
+    }
+}
+
+impl Storage for MemStorage {
+    type Guard = MemStorageGuard;
+
+    fn lock(&mut self) -> Result<MemStorageGuard> {
+        self.lock_by(false, true)
+    }
+
+    fn try_lock(&mut self) -> Result<MemStorageGuard> {
+        self.lock_by(false, false)
+    }
+
+    fn lock_shared(&mut self) -> Result<MemStorageGuard> {
+        self.lock_by(true, true)
+    }
+
+    fn try_lock_shared(&mut self) -> Result<MemStorageGuard> {
+        self.lock_by(true, false)
+    }
+
+    fn node_cache(&mut self) -> Option<&mut NodeCache> {
+        Some(&mut self.cache)
+    }
+
+    fn get_write_addr(&mut self) -> Result<u64> {
+        Ok(self.file().data.len() as u64)
+    }
+
+    fn get_root_addr(&mut self) -> Result<Option<u64>> {
+        if self.is_moved() {
+            self.reopen();
+        }
+        Ok(self.file().meta.root_addr)
+    }
+
+    fn get_commit_addr(&mut self) -> Result<Option<u64>> {
+        if self.is_moved() {
+            self.reopen();
+        }
+        Ok(self.file().meta.commit_addr)
+    }
+
+    fn get_comparator(&mut self) -> Result<Option<String>> {
+        if self.is_moved() {
+            self.reopen();
+        }
+        Ok(self.file().meta.comparator.clone())
+    }
+
+    fn set_comparator(&mut self, name: &str) -> Result<()> {
+        self.file().meta.comparator = Some(name.to_owned());
+        Ok(())
+    }
+
+    fn commit_root_addr(&mut self, addr: u64) -> Result<()> {
+        let commit = self.file().meta.next_commit(addr);
+        let commit_addr = self.write_record::<SerdeBincode, _>(&commit)?;
+        self.file().meta.apply(&commit, commit_addr);
+        Ok(())
+    }
+
+    fn create_compacted(&mut self) -> Result<MemStorage> {
+        let compacted = MemStorage::new();
+        {
+            let file = self.file();
+            let mut compacted_file = compacted.file();
+            compacted_file.meta.version = file.meta.version;
+            compacted_file.meta.comparator = file.meta.comparator.clone();
+        }
+        Ok(compacted)
+    }
+
+    /// The path of every clone leads to the compacted data from now on, while
+    /// the old data is kept for those still reading it.
+    fn replace_with(&mut self, compacted: MemStorage) -> Result<()> {
+        *self.path.lock().unwrap() = compacted.file.clone();
+        self.file().meta.moved = true;
+        self.file = compacted.file;
+        self.cache.clear();
+        self.generation += 1;
+        debug!("[Storage] memory storage is replaced by a compacted one");
+        Ok(())
+    }
 
-        Ok(SerdeBincode::to_writer(&mut self.file, &meta)?)
+    fn generation(&self) -> u64 {
+        self.generation
     }
 }
 
 #[cfg(test)]
 mod storage_test {
-    use super::{FileStorage, Storage, SUPERBLOCK};
+    use super::{
+        CorruptionError, FileStorage, FormatError, MemStorage, Storage, FORMAT_VERSION, SUPERBLOCK,
+    };
+    use crate::serde_interface::SerdeJson;
     use std::io::{Read, Seek, SeekFrom, Write};
     use std::thread;
     use std::time;
@@ -211,7 +1082,7 @@ mod storage_test {
 
         let start = time::Instant::now();
         let handle = thread::spawn(move || -> time::Duration {
-            let storage = FileStorage::new(path).unwrap();
+            let mut storage = FileStorage::new(path).unwrap();
             let mut guard = storage.lock().unwrap();
             let d = start.elapsed();
             guard.write_all(b" world").unwrap();
@@ -224,9 +1095,10 @@ mod storage_test {
         match handle.join() {
             Ok(d) => assert!(
                 d >= one_sec,
-                format!("another process did't block for enough time, only {:?}", d)
+                "another process did't block for enough time, only {:?}",
+                d
             ),
-            Err(e) => assert!(false, format!("something wrong: {:?}", e)),
+            Err(e) => panic!("something wrong: {:?}", e),
         }
 
         let mut record = String::new();
@@ -235,6 +1107,33 @@ mod storage_test {
         assert_eq!("hello world", record);
     }
 
+    // two readers go together, but never along with a writer
+    fn check_lock_shared<S: Storage>(a: &mut S, b: &mut S, c: &mut S) {
+        let reader = a.lock_shared().unwrap();
+        let another_reader = b.try_lock_shared().unwrap();
+        assert!(c.try_lock().is_err());
+        drop(reader);
+        assert!(c.try_lock().is_err());
+        drop(another_reader);
+        let writer = c.try_lock().unwrap();
+        assert!(a.try_lock_shared().is_err());
+        drop(writer);
+        a.try_lock_shared().unwrap();
+    }
+
+    #[test]
+    fn test_storage_lock_shared() {
+        let path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+        let mut a = FileStorage::new(&path).unwrap();
+        let mut b = FileStorage::new(&path).unwrap();
+        let mut c = FileStorage::new(&path).unwrap();
+        check_lock_shared(&mut a, &mut b, &mut c);
+
+        let storage = MemStorage::new();
+        let (mut a, mut b, mut c) = (storage.clone(), storage.clone(), storage);
+        check_lock_shared(&mut a, &mut b, &mut c);
+    }
+
     #[test]
     fn test_storage_superblock() {
         let path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
@@ -252,4 +1151,101 @@ mod storage_test {
         storage.write_all(b"hello world").unwrap();
         assert_eq!(SUPERBLOCK + 11, storage.get_write_addr().unwrap());
     }
+
+    #[test]
+    fn test_storage_checksum() {
+        let path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+        let mut storage = FileStorage::new(&path).unwrap();
+        let addr = storage.write_record::<SerdeJson, _>(&"hello").unwrap();
+        let value: String = storage.read_record::<SerdeJson, _>(addr).unwrap();
+        assert_eq!("hello", value);
+
+        // flip a bit of the data
+        storage.seek(SeekFrom::Start(addr + 10)).unwrap();
+        let mut byte = [0u8];
+        storage.read_exact(&mut byte).unwrap();
+        let mut file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+        file.seek(SeekFrom::Start(addr + 10)).unwrap();
+        file.write_all(&[byte[0] ^ 1]).unwrap();
+
+        let err = storage.read_record::<SerdeJson, String>(addr).unwrap_err();
+        assert_eq!(
+            Some(&CorruptionError { addr }),
+            err.downcast_ref::<CorruptionError>()
+        );
+        // an addr out of the file
+        let err = storage
+            .read_record::<SerdeJson, String>(addr + 100)
+            .unwrap_err();
+        assert!(err.downcast_ref::<CorruptionError>().is_some());
+    }
+
+    #[test]
+    fn test_storage_corrupted_superblock() {
+        let path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+        let mut storage = FileStorage::new(&path).unwrap();
+        storage.commit_root_addr(42).unwrap();
+        let mut file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+        file.seek(SeekFrom::Start(17)).unwrap();
+        file.write_all(&[0xff]).unwrap();
+        let err = storage.get_root_addr().unwrap_err();
+        assert_eq!(
+            Some(&CorruptionError { addr: 0 }),
+            err.downcast_ref::<CorruptionError>()
+        );
+    }
+
+    #[test]
+    fn test_storage_format_version() {
+        let path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+        let mut storage = FileStorage::new(&path).unwrap();
+        storage.commit_root_addr(42).unwrap();
+        let mut file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+        file.seek(SeekFrom::Start(4)).unwrap();
+        file.write_all(&(FORMAT_VERSION + 1).to_le_bytes()).unwrap();
+        let err = storage.get_root_addr().unwrap_err();
+        assert_eq!(
+            Some(&FormatError {
+                found: Some(FORMAT_VERSION + 1)
+            }),
+            err.downcast_ref::<FormatError>()
+        );
+
+        // files before format versions, even with a zeroed superblock
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.write_all(&[0; SUPERBLOCK as usize]).unwrap();
+        let err = FileStorage::new(&path)
+            .and_then(|mut storage| storage.get_root_addr())
+            .unwrap_err();
+        assert_eq!(
+            Some(&FormatError { found: None }),
+            err.downcast_ref::<FormatError>()
+        );
+    }
+
+    #[test]
+    fn test_mem_storage() {
+        let mut storage = MemStorage::new();
+        let mut another = storage.clone();
+        let guard = storage.lock().unwrap();
+        assert!(another.try_lock().is_err());
+
+        let addr = storage.write_record::<SerdeJson, _>(&"hello").unwrap();
+        storage.commit_root_addr(addr).unwrap();
+        drop(guard);
+        // clones share the data and the lock
+        assert_eq!(Some(addr), another.get_root_addr().unwrap());
+        let value: String = another.read_record::<SerdeJson, _>(addr).unwrap();
+        assert_eq!("hello", value);
+
+        let guard = storage.lock().unwrap();
+        let handle = thread::spawn(move || {
+            let _guard = another.lock().unwrap();
+            another.get_root_addr().unwrap()
+        });
+        thread::sleep(time::Duration::from_millis(100));
+        storage.commit_root_addr(0).unwrap();
+        drop(guard);
+        assert_eq!(None, handle.join().unwrap());
+    }
 }